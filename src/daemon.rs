@@ -0,0 +1,520 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::iter;
+use std::net::Shutdown;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use config::{Config, Value};
+use libc;
+
+use utils::{load_config, report_errors, Error};
+
+use modules::{Environment, GitCache, ENV_VARS};
+
+use {render_prompt, PromptArgs};
+
+/// How long the shell waits for the daemon before drawing the prompt
+/// itself, in milliseconds
+const CLIENT_TIMEOUT: u64 = 1000;
+
+/// How long the daemon waits for a shell to finish sending its
+/// request, in milliseconds
+const SERVER_TIMEOUT: u64 = 1000;
+
+/// How many config files the daemon remembers
+const MAX_CONFIGS: usize = 16;
+
+/// A prompt drawn by the daemon
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub prompt: String,
+    /// Errors that should be printed by the shell, since there's no
+    /// `error_log` to write them to
+    pub errors: Vec<String>,
+}
+
+/// Decides where the daemon's socket lives: `path` if given, otherwise
+/// `$CONTRAIL_SOCKET`, or a `contrail` directory in `$XDG_RUNTIME_DIR`
+/// (or in the temporary directory, named after the user).
+pub fn socket_path(path: Option<&str>) -> PathBuf {
+    if let Some(path) = path {
+        PathBuf::from(path)
+    } else if let Some(path) = env::var_os("CONTRAIL_SOCKET") {
+        PathBuf::from(path)
+    } else if let Some(dir) = env::var_os("XDG_RUNTIME_DIR") {
+        PathBuf::from(dir).join("contrail").join("contrail.sock")
+    } else {
+        let uid = unsafe { libc::getuid() };
+        env::temp_dir()
+            .join(format!("contrail-{}", uid))
+            .join("contrail.sock")
+    }
+}
+
+/// Whether `path` belongs to the current user, and can't be used by
+/// anyone else
+fn is_private(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|m| m.uid() == unsafe { libc::getuid() } && m.mode() & 0o077 == 0)
+        .unwrap_or(false)
+}
+
+/// Asks the daemon listening on `socket` to draw a prompt.
+///
+/// Returns `None` if there's no daemon, if the socket belongs to
+/// somebody else (who would get to see the environment and decide
+/// what the prompt runs), or if the daemon doesn't answer in time.
+pub fn request(socket: &Path, args: &PromptArgs, env: &Environment) -> Option<Response> {
+    if !is_private(socket) {
+        return None;
+    }
+
+    let mut stream = UnixStream::connect(socket).ok()?;
+
+    let timeout = Some(Duration::from_millis(CLIENT_TIMEOUT));
+    stream.set_read_timeout(timeout).ok()?;
+    stream.set_write_timeout(timeout).ok()?;
+
+    stream.write_all(&encode_request(args, env)).ok()?;
+    stream.shutdown(Shutdown::Write).ok()?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).ok()?;
+    decode_response(&response)
+}
+
+/// Listens for requests on `socket` until the process is killed.
+///
+/// The socket's directory is created if it doesn't exist yet, so that
+/// nobody else can use it.
+///
+/// Returns an `Error` if another daemon is already listening there, if
+/// other users have access to the directory, or if the socket can't be
+/// created.
+pub fn run(socket: &Path) -> io::Result<()> {
+    let dir = match socket.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    if !dir.exists() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+    }
+    if !is_private(dir) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  format!("{} must belong to you, and nobody else may have \
+                                           access to it",
+                                          dir.display())));
+    }
+
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                      format!("a daemon is already listening on {}",
+                                              socket.display())));
+        }
+
+        // Left behind by a daemon that didn't get to clean up
+        fs::remove_file(socket)?;
+    }
+
+    // The directory keeps everyone else out until the socket itself
+    // is locked down
+    let listener = UnixListener::bind(socket)?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+
+    serve(&listener, &Arc::new(Daemon::default()));
+    Ok(())
+}
+
+/// Answers every request sent to `listener`, each on its own thread.
+fn serve(listener: &UnixListener, daemon: &Arc<Daemon>) {
+    for stream in listener.incoming().filter_map(Result::ok) {
+        let daemon = Arc::clone(daemon);
+        // There's nobody left to tell if answering fails
+        thread::spawn(move || { let _ = daemon.handle(stream); });
+    }
+}
+
+/// Everything the daemon remembers between prompts
+#[derive(Default)]
+struct Daemon {
+    configs: Mutex<HashMap<PathBuf, CachedConfig>>,
+    /// How many config files have been parsed so far
+    loads: AtomicUsize,
+    git_cache: GitCache,
+}
+
+/// A parsed config file, along with when the file was last modified
+struct CachedConfig {
+    modified: Option<SystemTime>,
+    /// How many config files had been parsed before this one
+    loaded: usize,
+    config: Arc<Config>,
+}
+
+impl Daemon {
+    fn handle(&self, mut stream: UnixStream) -> io::Result<()> {
+        stream
+            .set_read_timeout(Some(Duration::from_millis(SERVER_TIMEOUT)))?;
+
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request)?;
+
+        let (args, env) = decode_request(&request);
+        stream.write_all(&encode_response(&self.render(&args, &env)))
+    }
+
+    /// Draws a prompt just like a shell would have without the daemon.
+    fn render(&self, args: &PromptArgs, env: &Environment) -> Response {
+        // Relative paths come from the shell, not from the daemon
+        let cwd = env.current_dir.clone().unwrap_or_default();
+
+        let mut errors: Vec<Error> = Vec::new();
+
+        let c = if let Some(ref f) = args.config {
+            self.config(&cwd.join(f)).unwrap_or_else(|err| {
+                                                          errors.push(err);
+                                                          Arc::new(Config::new())
+                                                      })
+        } else {
+            Arc::new(Config::new())
+        };
+
+        let prompt = render_prompt(args, &c, env, Some(&self.git_cache), &mut errors);
+
+        // Errors only make it back to the shell if they can't be
+        // written to the log file from here
+        let errors = if let Some(log_file) = c.get("global.error_log").and_then(Value::into_str) {
+            let log_file = cwd.join(log_file);
+            report_errors(&errors, log_file.to_str());
+            Vec::new()
+        } else {
+            errors.iter().map(|err| err.to_string()).collect()
+        };
+
+        Response {
+            prompt: prompt,
+            errors: errors,
+        }
+    }
+
+    /// Loads the config file at `path`, or reuses the copy that was
+    /// loaded last time if the file hasn't been modified since.
+    ///
+    /// Returns an `Error` if the file can't be parsed.
+    fn config(&self, path: &Path) -> Result<Arc<Config>, Error> {
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+
+        {
+            let configs = self.configs.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(cached) = configs.get(path) {
+                if cached.modified == modified {
+                    return Ok(Arc::clone(&cached.config));
+                }
+            }
+        }
+
+        let config = Arc::new(load_config(&path.to_string_lossy())?);
+        let loaded = self.loads.fetch_add(1, Ordering::SeqCst);
+
+        // Make room by forgetting the file that was parsed longest ago
+        let mut configs = self.configs.lock().unwrap_or_else(|e| e.into_inner());
+        if configs.len() >= MAX_CONFIGS && !configs.contains_key(path) {
+            let oldest = configs
+                .iter()
+                .min_by_key(|&(_, cached)| cached.loaded)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                configs.remove(&oldest);
+            }
+        }
+
+        configs.insert(path.to_path_buf(),
+                       CachedConfig {
+                           modified: modified,
+                           loaded: loaded,
+                           config: Arc::clone(&config),
+                       });
+        Ok(config)
+    }
+}
+
+// Requests are a list of `key=value` records, and responses are the
+// prompt followed by every error. Every field ends with a NUL byte,
+// which can't be part of an argument, a path or an environment
+// variable. The working directory is sent as is, since it doesn't have
+// to be valid UTF-8.
+
+fn encode_request(args: &PromptArgs, env: &Environment) -> Vec<u8> {
+    let mut fields: Vec<(String, &[u8])> = Vec::new();
+
+    let arg_fields = [("config", &args.config),
+                      ("shell", &args.shell),
+                      ("exit_code", &args.exit_code),
+                      ("jobs", &args.jobs),
+                      ("duration", &args.duration),
+                      ("side", &args.side),
+                      ("width", &args.width)];
+    for &(key, value) in &arg_fields {
+        if let Some(ref value) = *value {
+            fields.push((key.to_string(), value.as_bytes()));
+        }
+    }
+
+    if let Some(ref cwd) = env.current_dir {
+        fields.push((String::from("cwd"), cwd.as_os_str().as_bytes()));
+    }
+
+    for (name, value) in &env.vars {
+        fields.push((format!("env.{}", name), value.as_bytes()));
+    }
+
+    let mut request = Vec::new();
+    for (key, value) in fields {
+        request.extend_from_slice(key.as_bytes());
+        request.push(b'=');
+        request.extend_from_slice(value);
+        request.push(0);
+    }
+    request
+}
+
+/// Reads a request. Anything that isn't understood is ignored.
+fn decode_request(request: &[u8]) -> (PromptArgs, Environment) {
+    let mut args = PromptArgs::default();
+    let mut env = Environment::default();
+
+    for field in request.split(|&b| b == 0) {
+        let mut parts = field.splitn(2, |&b| b == b'=');
+        let (key, bytes) = match (parts.next(), parts.next()) {
+            (Some(key), Some(bytes)) => (String::from_utf8_lossy(key), bytes),
+            _ => continue,
+        };
+        let value = String::from_utf8_lossy(bytes).into_owned();
+
+        match &*key {
+            "config" => args.config = Some(value),
+            "shell" => args.shell = Some(value),
+            "exit_code" => args.exit_code = Some(value),
+            "jobs" => args.jobs = Some(value),
+            "duration" => args.duration = Some(value),
+            "side" => args.side = Some(value),
+            "width" => args.width = Some(value),
+            "cwd" => env.current_dir = Some(PathBuf::from(OsStr::from_bytes(bytes))),
+            _ if key.starts_with("env.") => {
+                let name = &key["env.".len()..];
+                if ENV_VARS.contains(&name) {
+                    env.vars.insert(name.to_string(), value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    (args, env)
+}
+
+fn encode_response(response: &Response) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for field in iter::once(&response.prompt).chain(&response.errors) {
+        bytes.extend_from_slice(field.as_bytes());
+        bytes.push(0);
+    }
+    bytes
+}
+
+/// Reads a response.
+///
+/// Returns `None` if the response was cut short.
+fn decode_response(response: &[u8]) -> Option<Response> {
+    if response.last() != Some(&0) {
+        return None;
+    }
+
+    let response = String::from_utf8_lossy(&response[..response.len() - 1]);
+    let mut fields = response.split('\0').map(String::from);
+
+    Some(Response {
+             prompt: fields.next()?,
+             errors: fields.collect(),
+         })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempdir::TempDir;
+
+    fn args(config: &Path) -> PromptArgs {
+        PromptArgs {
+            config: config.to_str().map(String::from),
+            shell: Some(String::from("zsh")),
+            exit_code: Some(String::from("1")),
+            jobs: None,
+            duration: Some(String::from("oops")),
+            side: Some(String::from("left")),
+            width: Some(String::from("80")),
+        }
+    }
+
+    #[test]
+    fn test_encode_request() {
+        let args = args(Path::new("contrail.toml"));
+        let mut vars = HashMap::new();
+        vars.insert(String::from("HOME"), String::from("/home/user"));
+        let env = Environment {
+            current_dir: Some(PathBuf::from("/home/user")),
+            vars: vars,
+        };
+
+        assert_eq!(decode_request(&encode_request(&args, &env)), (args, env));
+
+        // The working directory doesn't have to be valid UTF-8
+        let env = Environment {
+            current_dir: Some(PathBuf::from(OsStr::from_bytes(b"/home/\xff"))),
+            ..Environment::default()
+        };
+        let (_, decoded) = decode_request(&encode_request(&PromptArgs::default(), &env));
+        assert_eq!(decoded, env);
+
+        // Variables that modules aren't allowed to read are dropped
+        let (_, env) = decode_request(b"env.SECRET=hunter2\0env.PWD=/\0garbage\0");
+        assert_eq!(env.var("SECRET"), None);
+        assert_eq!(env.var("PWD"), Some("/"));
+    }
+
+    #[test]
+    fn test_encode_response() {
+        let response = Response {
+            prompt: String::from("$ "),
+            errors: vec![String::from("first"), String::from("second")],
+        };
+        assert_eq!(decode_response(&encode_response(&response)),
+                   Some(response));
+
+        let response = Response {
+            prompt: String::new(),
+            errors: Vec::new(),
+        };
+        assert_eq!(decode_response(&encode_response(&response)),
+                   Some(response));
+
+        // Cut short
+        assert_eq!(decode_response(b""), None);
+        assert_eq!(decode_response(b"$ \0fir"), None);
+    }
+
+    /// Starts a daemon listening on `socket`, and waits until it's
+    /// ready
+    fn start_daemon(socket: &Path) {
+        let path = socket.to_path_buf();
+        thread::spawn(move || run(&path));
+
+        for _ in 0..100 {
+            if is_private(socket) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the daemon didn't start");
+    }
+
+    #[test]
+    fn test_request() {
+        let dir = TempDir::new("contrail").unwrap();
+        let socket = dir.path().join("daemon").join("contrail.sock");
+        let config = dir.path().join("config.toml");
+        let env = Environment {
+            current_dir: Some(dir.path().to_path_buf()),
+            ..Environment::default()
+        };
+
+        // Nobody's listening yet
+        assert_eq!(request(&socket, &args(&config), &env), None);
+
+        start_daemon(&socket);
+
+        // The daemon draws the same prompt the shell would have, and
+        // sends back the errors
+        let mut errors = Vec::new();
+        let expected = render_prompt(&args(&config), &Config::new(), &env, None, &mut errors);
+        let response = request(&socket, &args(&config), &env).unwrap();
+        assert_eq!(response.prompt, expected);
+        assert_eq!(response.errors,
+                   errors
+                       .iter()
+                       .map(|err| err.to_string())
+                       .collect::<Vec<_>>());
+        assert_eq!(response.errors.len(), 1);
+
+        // A config file that shows up later isn't missed, and relative
+        // paths are relative to the shell's directory
+        fs::write(&config, "[global]\nmodules = [\"exit_code\"]\n").unwrap();
+        let response = request(&socket, &args(Path::new("config.toml")), &env).unwrap();
+        let c = load_config(config.to_str().unwrap()).unwrap();
+        assert_eq!(response.prompt,
+                   render_prompt(&args(&config), &c, &env, None, &mut Vec::new()));
+        assert!(response.prompt != expected);
+    }
+
+    #[test]
+    fn test_config_cache() {
+        let dir = TempDir::new("contrail").unwrap();
+        let daemon = Daemon::default();
+
+        let paths: Vec<_> = (0..MAX_CONFIGS + 1)
+            .map(|i| dir.path().join(format!("{}.toml", i)))
+            .collect();
+        for path in &paths {
+            fs::write(path, "").unwrap();
+            daemon.config(path).unwrap();
+        }
+
+        // The file parsed first made room for the last one
+        let configs = daemon.configs.lock().unwrap();
+        assert_eq!(configs.len(), MAX_CONFIGS);
+        assert!(!configs.contains_key(&paths[0]));
+        assert!(configs.contains_key(&paths[MAX_CONFIGS]));
+    }
+
+    #[test]
+    fn test_socket_permissions() {
+        let dir = TempDir::new("contrail").unwrap();
+        let env = Environment::default();
+        let args = args(&dir.path().join("config.toml"));
+
+        // The daemon makes its own directory, but won't share one
+        let private = dir.path().join("daemon");
+        start_daemon(&private.join("contrail.sock"));
+        assert_eq!(fs::metadata(&private).unwrap().mode() & 0o777, 0o700);
+
+        let shared = dir.path().join("shared");
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(run(&shared.join("contrail.sock")).unwrap_err().kind(),
+                   io::ErrorKind::PermissionDenied);
+
+        // Shells won't talk to a socket that others have access to
+        let socket = shared.join("contrail.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        thread::spawn(move || serve(&listener, &Arc::new(Daemon::default())));
+        fs::set_permissions(&socket, fs::Permissions::from_mode(0o666)).unwrap();
+        assert_eq!(request(&socket, &args, &env), None);
+        fs::set_permissions(&socket, fs::Permissions::from_mode(0o600)).unwrap();
+        assert!(request(&socket, &args, &env).is_some());
+    }
+}
@@ -0,0 +1,133 @@
+use clap::Shell;
+
+use VERSION;
+
+/// Where the integration scripts look for a config file, unless
+/// `$CONTRAIL_CONFIG` is set or another path is passed to `init`.
+const DEFAULT_CONFIG_PATH: &str = "$HOME/.config/contrail/config.toml";
+
+const BASH_INIT: &str = include_str!("init/contrail.bash");
+const ZSH_INIT: &str = include_str!("init/contrail.zsh");
+const FISH_INIT: &str = include_str!("init/contrail.fish");
+const POWERSHELL_INIT: &str = include_str!("init/contrail.ps1");
+
+/// Returns the code that sets up contrail as the prompt of `shell`.
+///
+/// The script passes the exit code, shell type, config path, number
+/// of background jobs, duration of the last command and width of the
+/// terminal to contrail every time the prompt is drawn. `config`, if
+/// present, replaces the default location of the config file.
+pub fn init_script(shell: Shell, config: Option<&str>) -> String {
+    let script = match shell {
+        Shell::Bash => BASH_INIT,
+        Shell::Zsh => ZSH_INIT,
+        Shell::Fish => FISH_INIT,
+        Shell::PowerShell => POWERSHELL_INIT,
+        _ => panic!("Your shell is not supported yet!"),
+    };
+
+    script
+        .replace("{{version}}", VERSION)
+        .replace("{{config}}", config.unwrap_or(DEFAULT_CONFIG_PATH))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    use std::thread;
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_init_script() {
+        let shells = [(Shell::Bash, "bash"),
+                      (Shell::Zsh, "zsh"),
+                      (Shell::Fish, "fish"),
+                      (Shell::PowerShell, "powershell")];
+
+        for &(shell, name) in &shells {
+            let script = init_script(shell, None);
+
+            // Every placeholder must have been filled in
+            assert!(!script.contains("{{"));
+            assert!(script.contains(&format!("contrail {} integration", VERSION)));
+            assert!(script.contains(DEFAULT_CONFIG_PATH));
+
+            // Everything contrail needs to know is passed along
+            assert!(script.contains(&format!("--shell {}", name)));
+            for arg in &["--exit_code", "--config", "--jobs", "--duration", "--width"] {
+                assert!(script.contains(arg));
+            }
+        }
+
+        // Shells with a right-aligned prompt draw that side too
+        for &shell in &[Shell::Zsh, Shell::Fish] {
+            assert!(init_script(shell, None).contains("--side right"));
+        }
+
+        // A custom config path replaces the default one
+        let script = init_script(Shell::Bash, Some("/etc/contrail.toml"));
+        assert!(script.contains("/etc/contrail.toml"));
+        assert!(!script.contains(DEFAULT_CONFIG_PATH));
+    }
+    /// Runs the bash script in a real interactive bash, which has to be
+    /// version 5 or later to time commands at all. It takes a couple of
+    /// seconds, so it only runs with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_bash_timing() {
+        let tmp = TempDir::new("contrail").unwrap();
+        let script = tmp.path().join("contrail.bash");
+        let args = tmp.path().join("args");
+        let chained = tmp.path().join("chained");
+        File::create(&script)
+            .unwrap()
+            .write_all(init_script(Shell::Bash, None).as_bytes())
+            .unwrap();
+
+        let mut bash = Command::new("bash")
+            .args(["--norc", "--noprofile", "-i"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("bash isn't installed");
+
+        {
+            let stdin = bash.stdin.as_mut().unwrap();
+
+            // contrail is replaced by something that writes down what
+            // it's passed. The user's own PROMPT_COMMAND and DEBUG trap
+            // have to keep working.
+            writeln!(stdin, "contrail() {{ echo \"$@\" >> '{}'; }}", args.display()).unwrap();
+            writeln!(stdin, "PROMPT_COMMAND='true; true'").unwrap();
+            writeln!(stdin,
+                     "trap '[ \"$BASH_COMMAND\" = \"sleep 0.2\" ] && echo >> {}' DEBUG",
+                     chained.display())
+                    .unwrap();
+            writeln!(stdin, "eval \"$(cat '{}')\"", script.display()).unwrap();
+            stdin.flush().unwrap();
+
+            // Time spent at the prompt isn't part of the next command
+            thread::sleep(Duration::from_millis(1000));
+            writeln!(stdin, "sleep 0.2").unwrap();
+            writeln!(stdin, "exit").unwrap();
+        }
+        bash.wait().unwrap();
+
+        let args = fs::read_to_string(&args).unwrap();
+        let duration: u64 = args.lines()
+            .last()
+            .and_then(|line| line.split("--duration ").nth(1))
+            .and_then(|rest| rest.split(' ').next())
+            .and_then(|duration| duration.parse().ok())
+            .unwrap();
+        assert!((200..1000).contains(&duration), "took {}ms", duration);
+        assert!(chained.exists());
+    }
+}
@@ -0,0 +1,79 @@
+# contrail {{version}} integration for bash
+#
+# Load it from your ~/.bashrc with:
+#     eval "$(contrail init bash)"
+
+: "${CONTRAIL_CONFIG:={{config}}}"
+
+# Commands are timed through the DEBUG trap. Any trap that was set
+# before is kept, and run first.
+#
+# `trap -p` prints `trap -- '<command>' <signal>` lines that can be run
+# again, which is the easiest way to get the command back out. It has
+# to be run on its own, since command substitutions don't inherit the
+# DEBUG trap otherwise.
+__contrail_traps=$(trap -p)
+__contrail_old_trap=
+trap() {
+    if [ "$3" = DEBUG ]; then
+        __contrail_old_trap=$2
+    fi
+}
+eval "$__contrail_traps"
+unset -f trap
+
+# Loading this twice mustn't chain the trap to itself
+if [ "$__contrail_old_trap" != "__contrail_preexec" ]; then
+    __contrail_debug_trap=$__contrail_old_trap
+fi
+unset __contrail_traps __contrail_old_trap
+
+__contrail_preexec() {
+    if [ -n "$__contrail_debug_trap" ]; then
+        eval "$__contrail_debug_trap"
+    fi
+
+    # The trap also fires for every command run by PROMPT_COMMAND, so
+    # only the first command after the prompt is drawn starts the timer
+    if [ -n "$__contrail_timing" ]; then
+        return
+    fi
+    __contrail_timing=1
+
+    # $EPOCHREALTIME is only available in bash 5 and later. Without it,
+    # durations are always reported as 0.
+    __contrail_start=${EPOCHREALTIME/[.,]/}
+}
+
+# Runs first in PROMPT_COMMAND, so that it sees the exit code of the
+# last command
+__contrail_prompt() {
+    local exit_code=$?
+
+    local duration=0
+    local now=${EPOCHREALTIME/[.,]/}
+    if [ -n "$__contrail_start" ] && [ -n "$now" ]; then
+        duration=$(( (now - __contrail_start) / 1000 ))
+    fi
+
+    local jobs
+    jobs=$(jobs -p | wc -l)
+
+    PS1="$(contrail --exit_code "$exit_code" \
+                    --shell bash \
+                    --config "$CONTRAIL_CONFIG" \
+                    --jobs $jobs \
+                    --duration "$duration" \
+                    ${COLUMNS:+--width "$COLUMNS"}) "
+}
+
+# Runs last in PROMPT_COMMAND. Until then, the commands that come
+# after `__contrail_prompt` mustn't start the timer, or the next
+# command would be timed from when the prompt was drawn.
+__contrail_reset() {
+    __contrail_start=
+    __contrail_timing=
+}
+
+trap '__contrail_preexec' DEBUG
+PROMPT_COMMAND="__contrail_prompt${PROMPT_COMMAND:+; $PROMPT_COMMAND}; __contrail_reset"
@@ -0,0 +1,41 @@
+# contrail {{version}} integration for fish
+#
+# Load it from your ~/.config/fish/config.fish with:
+#     contrail init fish | source
+
+set -q CONTRAIL_CONFIG; or set -g CONTRAIL_CONFIG "{{config}}"
+
+# Draws one side of the prompt. The first argument is the exit code of
+# the last command, and the rest are passed on to contrail.
+function __contrail_prompt
+    set -l exit_code $argv[1]
+    set -e argv[1]
+
+    set -l jobs 0
+    if jobs -q
+        set jobs (count (jobs -p))
+    end
+
+    # fish already times every command for us
+    set -l duration 0
+    if set -q CMD_DURATION
+        set duration $CMD_DURATION
+    end
+
+    contrail --exit_code $exit_code \
+             --shell fish \
+             --config "$CONTRAIL_CONFIG" \
+             --jobs $jobs \
+             --duration $duration \
+             --width $COLUMNS \
+             $argv
+end
+
+function fish_prompt
+    __contrail_prompt $status
+    echo -n ' '
+end
+
+function fish_right_prompt
+    __contrail_prompt $status --side right
+end
@@ -0,0 +1,25 @@
+# contrail {{version}} integration for PowerShell
+#
+# Load it from your $PROFILE with:
+#     Invoke-Expression (& contrail init powershell | Out-String)
+
+if (-not $env:CONTRAIL_CONFIG) { $env:CONTRAIL_CONFIG = "{{config}}" }
+
+function global:prompt {
+    # $? is false if the last command failed, while native programs
+    # report their actual exit code through $LASTEXITCODE
+    $code = if ($?) { 0 } elseif ($LASTEXITCODE) { $LASTEXITCODE -band 0xFF } else { 1 }
+
+    $jobs = @(Get-Job -State Running).Count
+
+    # Only report the duration once, not every time Enter is pressed
+    # on an empty line
+    $duration = 0
+    $last = Get-History -Count 1
+    if ($last -and $last.Id -ne $global:__contrail_last_id) {
+        $global:__contrail_last_id = $last.Id
+        $duration = [long]($last.EndExecutionTime - $last.StartExecutionTime).TotalMilliseconds
+    }
+
+    "$(contrail --exit_code $code --shell powershell --config $env:CONTRAIL_CONFIG --jobs $jobs --duration $duration --width $Host.UI.RawUI.WindowSize.Width) "
+}
@@ -0,0 +1,733 @@
+use std::cmp;
+use std::iter;
+use std::str::FromStr;
+
+use ansi_term::{ANSIString, ANSIStrings, Color, Style};
+use clap::Shell;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use utils::FormatResult;
+
+use modules::{segments_width, style_from_modulestyle, ModuleOptions, ModuleStyle, Segment};
+
+/// Name of the entry in `global.modules` that ends the current line of
+/// modules and starts a new one
+pub const LINE_BREAK: &str = "newline";
+
+/// Shown in place of the part of a module that was cut off to make the
+/// prompt fit on the line
+const ELLIPSIS: &str = "…";
+
+/// Side of the line a prompt is drawn on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    /// The regular prompt, in front of the cursor
+    Left,
+    /// The right-aligned prompt that zsh and fish support. Separators
+    /// are mirrored, so that they point to the left.
+    Right,
+}
+
+impl FromStr for Side {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Side, ()> {
+        match s {
+            "left" => Ok(Side::Left),
+            "right" => Ok(Side::Right),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Background colors of the visible modules on either side of a
+/// module. Separators are drawn in these colors.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Neighbours {
+    /// Background color, if any, of the previous visible module
+    pub prev_bg: Option<Color>,
+    /// Background color, if any, of the next visible module
+    pub next_bg: Option<Color>,
+}
+
+/// Draws the prompt out of the rendered modules, in the order they
+/// appear in, for one `side` of the line. If `max_width` is given,
+/// modules are dropped or shrunk until the prompt is no wider than
+/// that many columns.
+///
+/// Modules without any output are left out entirely, so the modules
+/// on either side of them become neighbours.
+pub fn draw(results: Vec<FormatResult>,
+            side: Side,
+            shell: Shell,
+            max_width: Option<usize>)
+            -> String {
+    let mut visible: Vec<(Vec<Segment>, ModuleOptions)> = results
+        .into_iter()
+        .filter_map(|result| {
+            let mut options = result.options;
+            result
+                .output
+                .map(|segments| {
+                         // The output has to be measured like any
+                         // other content
+                         match options.output.take() {
+                             Some(output) => (vec![Segment::new(output)], options),
+                             None => (segments, options),
+                         }
+                     })
+        })
+        .collect();
+
+    if let Some(max_width) = max_width {
+        fit(&mut visible, side, max_width);
+    }
+
+    let formatted_strings: Vec<ANSIString<'static>> = (0..visible.len())
+        .map(|i| neighbours(&visible, i))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .zip(visible)
+        .map(|(neighbours, (segments, options))| {
+                 format_for_module(segments, &options, neighbours, side, shell)
+             })
+        .collect();
+
+    format!("{}", ANSIStrings(formatted_strings.as_slice()))
+}
+
+/// Finds the backgrounds of the modules next to the `i`th one.
+fn neighbours(modules: &[(Vec<Segment>, ModuleOptions)], i: usize) -> Neighbours {
+    let background = |j: usize| modules.get(j).and_then(|module| module.1.style.background);
+    Neighbours {
+        prev_bg: if i > 0 { background(i - 1) } else { None },
+        next_bg: background(i + 1),
+    }
+}
+
+/// Drops or shrinks modules until they take up at most `max_width`
+/// columns.
+///
+/// Modules with the lowest priority go first. Among modules with the
+/// same priority, the one furthest away from the cursor goes first. A
+/// module with a `min_width` is shrunk as far as that allows before
+/// it's dropped.
+fn fit(modules: &mut Vec<(Vec<Segment>, ModuleOptions)>, side: Side, max_width: usize) {
+    loop {
+        let width: usize = (0..modules.len())
+            .map(|i| module_width(&modules[i].0, &modules[i].1, neighbours(modules, i), side))
+            .sum();
+        if width <= max_width {
+            return;
+        }
+
+        // The cursor follows the left side of the line, and precedes
+        // the right side
+        let mut candidates: Vec<usize> = (0..modules.len()).collect();
+        if side == Side::Right {
+            candidates.reverse();
+        }
+        let victim = match candidates
+                  .into_iter()
+                  .min_by_key(|&i| modules[i].1.priority) {
+            Some(victim) => victim,
+            None => return,
+        };
+
+        let content_width = segments_width(&modules[victim].0);
+        match modules[victim].1.min_width {
+            Some(min_width) if content_width > min_width => {
+                let target = cmp::max(min_width, content_width.saturating_sub(width - max_width));
+                let (segments, options) = modules.remove(victim);
+                modules.insert(victim, (shrink(segments, target), options));
+            }
+            _ => {
+                modules.remove(victim);
+            }
+        }
+    }
+}
+
+/// Number of columns a module takes up once it's drawn, separators
+/// included
+fn module_width(segments: &[Segment],
+                options: &ModuleOptions,
+                neighbours: Neighbours,
+                side: Side)
+                -> usize {
+    let (separator, _) = separator_for(options, neighbours, side);
+    options.separator_left.width() + options.padding_left.width() + segments_width(segments) +
+    options.padding_right.width() + separator.width()
+}
+
+/// Cuts the start of `segments` off so that they're at most `width`
+/// columns wide, with an ellipsis in place of whatever was cut.
+fn shrink(segments: Vec<Segment>, width: usize) -> Vec<Segment> {
+    if segments_width(&segments) <= width {
+        return segments;
+    } else if width == 0 {
+        return Vec::new();
+    }
+
+    // Room has to be left for the ellipsis, which is styled like the
+    // segment that got cut
+    let mut room = width - ELLIPSIS.width();
+    let mut kept: Vec<Segment> = Vec::new();
+    let mut cut_style = ModuleStyle::default();
+    for segment in segments.into_iter().rev() {
+        let segment_width = segment.text.width();
+        if segment_width <= room {
+            room -= segment_width;
+            kept.push(segment);
+            continue;
+        }
+
+        let mut tail: Vec<char> = Vec::new();
+        for c in segment.text.chars().rev() {
+            let char_width = c.width().unwrap_or(0);
+            if char_width > room {
+                break;
+            }
+            room -= char_width;
+            tail.push(c);
+        }
+        if !tail.is_empty() {
+            kept.push(Segment::styled(tail.into_iter().rev().collect::<String>(), segment.style));
+        }
+        cut_style = segment.style;
+        break;
+    }
+
+    kept.push(Segment::styled(ELLIPSIS, cut_style));
+    kept.reverse();
+    kept
+}
+
+/// Formats the content of a module, made up of several
+/// differently-styled `Segment`s, with the given `ModuleOptions` for a
+/// specific `Shell`.
+///
+/// The style of each segment is layered on top of the module's style, so
+/// a segment only needs to set whatever makes it different.
+///
+/// # Parameters
+///
+/// - `segments` - the contents of the module to be formatted
+/// - `options` - the background, foreground, padding, etc. to apply
+/// - `neighbours` - the background colors of the visible modules around this one
+/// - `side` - the side of the line, which decides where the separators go
+/// - `shell` - the type of shell to format the string for
+pub fn format_for_module(segments: Vec<Segment>,
+                         options: &ModuleOptions,
+                         neighbours: Neighbours,
+                         side: Side,
+                         shell: Shell)
+                         -> ANSIString<'static> {
+    let segments = if let Some(ref output) = options.output {
+        // Override output if present
+        vec![Segment::new(output.as_str())]
+    } else {
+        segments
+    };
+
+    // The padding is styled like the module itself. Neighbouring
+    // pieces that end up with the same style are joined together, so
+    // that we don't print more escape-sequences than necessary.
+    let mut pieces: Vec<(String, ModuleStyle)> = Vec::new();
+    let all_segments = iter::once(Segment::new(options.padding_left.as_str()))
+        .chain(segments)
+        .chain(iter::once(Segment::new(options.padding_right.as_str())));
+    for segment in all_segments.filter(|segment| !segment.text.is_empty()) {
+        let style = segment.style.or(&options.style);
+        match pieces.last_mut() {
+            Some(&mut (ref mut text, ref last_style)) if *last_style == style => {
+                text.push_str(&segment.text);
+                continue;
+            }
+            _ => {}
+        }
+        pieces.push((segment.text, style));
+    }
+
+    // A left separator points into the previous module, so it's drawn
+    // on top of that module's background. On the right side, it's
+    // mirrored to point into the next module instead.
+    let separator_left_style = ModuleStyle {
+        foreground: options.style.background,
+        background: match side {
+            Side::Left => neighbours.prev_bg,
+            Side::Right => neighbours.next_bg,
+        },
+        text_properties: options.style.text_properties,
+    };
+
+    let (separator, separator_style) = separator_for(options, neighbours, side);
+
+    let content: String = pieces
+        .iter()
+        .map(|&(ref text, ref style)| paint(text, style, shell))
+        .collect();
+
+    let separator = paint(separator, &separator_style, shell);
+    let separator_left = paint(&options.separator_left, &separator_left_style, shell);
+    match side {
+        Side::Left => ANSIString::from(format!("{}{}{}", separator_left, content, separator)),
+        Side::Right => ANSIString::from(format!("{}{}{}", separator, content, separator_left)),
+    }
+}
+
+/// Picks the separator that's drawn after the module (or in front of
+/// it, on the right side), along with its style.
+fn separator_for(options: &ModuleOptions, neighbours: Neighbours, side: Side) -> (&str, ModuleStyle) {
+    // On the right side everything is mirrored, so the separator
+    // points into the previous module
+    let (separator, separator_bg) = match side {
+        Side::Left => (&options.separator, neighbours.next_bg),
+        Side::Right => (&options.separator_right, neighbours.prev_bg),
+    };
+
+    // Two modules with the same background would blend together
+    // behind a regular separator (which is drawn in that very color),
+    // so they're split by a thin separator in the module's own colors
+    // instead
+    let same_bg = options.style.background.is_some() && options.style.background == separator_bg;
+    match options.separator_thin {
+        Some(ref thin) if same_bg => (thin, options.style),
+        _ => {
+            (separator,
+             ModuleStyle {
+                 foreground: options.style.background,
+                 background: separator_bg,
+                 text_properties: options.style.text_properties,
+             })
+        }
+    }
+}
+
+/// Styles `text` for `shell`. Every time there is a color
+/// escape-sequence, it must be surrounded by the length escape-codes.
+fn paint(text: &str, style: &ModuleStyle, shell: Shell) -> String {
+    if text.is_empty() {
+        return String::new();
+    }
+
+    // Each shell keeps track of the number of characters that make up
+    // the prompt. The ANSI escape-sequences that color the text will
+    // be accidentally included in this length *unless* we prefix and
+    // suffix them with these shell-specific escape-sequences. We don't
+    // want the shell to mistakenly think there's fewer characters
+    // remaining on the current line than there actually are.
+    let style = style_from_modulestyle(style);
+    let (len_esc_prefix, len_esc_suffix) = if style == Style::default() {
+        // But if there aren't any escape-sequences that we need to
+        // escape, don't set the length escape codes because we don't
+        // want the shell to have to deal with them if they're
+        // unnecessary
+        ("", "")
+    } else {
+        len_esc_for_shell(shell)
+    };
+
+    format!("{}{}{}{}{}{}{}",
+            len_esc_prefix,
+            style.prefix(),
+            len_esc_suffix,
+            text,
+            len_esc_prefix,
+            style.suffix(),
+            len_esc_suffix)
+}
+
+/// Returns the escape sequences that must surround every color
+/// escape-sequence so that `shell` doesn't count them towards the
+/// length of the prompt.
+///
+/// Fish and PowerShell measure the width of the prompt on their own,
+/// so nothing needs to be escaped for them.
+fn len_esc_for_shell(shell: Shell) -> (&'static str, &'static str) {
+    match shell {
+        Shell::Bash => ("\\[", "\\]"),
+        Shell::Zsh => ("%{", "%}"),
+        Shell::Fish | Shell::PowerShell => ("", ""),
+        _ => panic!("Your shell is not supported yet!"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_for_module_powershell() {
+        let options = ModuleOptions {
+            output: None,
+            padding_left: String::new(),
+            padding_right: String::new(),
+            separator: String::from(">"),
+            style: ModuleStyle {
+                background: Some(Color::Blue),
+                foreground: None,
+                text_properties: None,
+            },
+            ..ModuleOptions::default()
+        };
+
+        // PowerShell prints the raw escape character (what `e expands
+        // to), and has no notion of length escape sequences
+        let formatted_string = format!("{}",
+                                       format_for_module(vec![Segment::new("PS")],
+                                                         &options,
+                                                         Neighbours::default(),
+                                                         Side::Left,
+                                                         Shell::PowerShell));
+        assert_eq!(formatted_string, "\x1B[44mPS\x1B[0m\x1B[34m>\x1B[0m");
+        assert!(!formatted_string.contains("\\["));
+        assert!(!formatted_string.contains("%{"));
+    }
+
+    #[test]
+    fn test_format_for_module() {
+        const CONTENT: &'static str = "Hello";
+        const PADDING: &'static str = " ";
+        const SEPARATOR: &'static str = ">";
+
+        let mut options = ModuleOptions {
+            output: None,
+            padding_left: PADDING.to_string(),
+            padding_right: PADDING.to_string(),
+            separator: SEPARATOR.to_string(),
+            style: ModuleStyle {
+                background: Some(Color::Blue),
+                foreground: Some(Color::White),
+                text_properties: Some(Style::default().bold()),
+            },
+            ..ModuleOptions::default()
+        };
+
+        let formatted_string = format_for_module(vec![Segment::new(CONTENT)],
+                                                 &options,
+                                                 Neighbours::default(),
+                                                 Side::Left,
+                                                 Shell::Bash);
+        assert_eq!(format!("\\[\x1B[1;44;37m\\]{}{}{}\\[\x1B[0m\\]\\[\x1B[1;34m\\]{}\\[\x1B[0m\\]",
+                           PADDING,
+                           CONTENT,
+                           PADDING,
+                           SEPARATOR),
+                   format!("{}", formatted_string));
+
+        // Override the output, use ZSH
+        options.output = Some(String::from("modified"));
+        let formatted_string = format_for_module(vec![Segment::new(CONTENT)],
+                                                 &options,
+                                                 Neighbours::default(),
+                                                 Side::Left,
+                                                 Shell::Bash);
+        assert_eq!(format!("\\[\x1B[1;44;37m\\]{}{}{}\\[\x1B[0m\\]\\[\x1B[1;34m\\]{}\\[\x1B[0m\\]",
+                           PADDING,
+                           "modified",
+                           PADDING,
+                           SEPARATOR),
+                   format!("{}", formatted_string));
+    }
+
+    #[test]
+    fn test_format_for_module_segments() {
+        let options = ModuleOptions {
+            output: None,
+            padding_left: String::from(" "),
+            padding_right: String::from(" "),
+            separator: String::from(">"),
+            style: ModuleStyle {
+                background: Some(Color::Blue),
+                foreground: Some(Color::White),
+                text_properties: None,
+            },
+            ..ModuleOptions::default()
+        };
+
+        let red = ModuleStyle {
+            background: None,
+            foreground: Some(Color::Red),
+            text_properties: None,
+        };
+        let segments = vec![Segment::new("master"),
+                            Segment::styled(" +", red),
+                            Segment::new(" 1")];
+
+        // Each segment is escaped separately, and only overrides the
+        // parts of the module's style that it sets
+        let formatted_string =
+            format_for_module(segments, &options, Neighbours::default(), Side::Left, Shell::Bash);
+        assert_eq!(format!("{}", formatted_string),
+                   "\\[\x1B[44;37m\\] master\\[\x1B[0m\\]\
+                    \\[\x1B[44;31m\\] +\\[\x1B[0m\\]\
+                    \\[\x1B[44;37m\\] 1 \\[\x1B[0m\\]\
+                    \\[\x1B[34m\\]>\\[\x1B[0m\\]");
+
+        // Segments styled the same as the module are merged together
+        let segments = vec![Segment::new("a"),
+                            Segment::styled("b", options.style),
+                            Segment::new("")];
+        assert_eq!(format_for_module(segments,
+                                     &options,
+                                     Neighbours::default(),
+                                     Side::Left,
+                                     Shell::Bash),
+                   format_for_module(vec![Segment::new("ab")],
+                                     &options,
+                                     Neighbours::default(),
+                                     Side::Left,
+                                     Shell::Bash));
+
+        // Segments that only set text properties still need to be
+        // escaped, while segments without any style don't
+        let options = ModuleOptions {
+            separator: String::new(),
+            style: ModuleStyle::default(),
+            ..options
+        };
+        let bold = ModuleStyle {
+            text_properties: Some(Style::new().bold()),
+            ..ModuleStyle::default()
+        };
+        let segments = vec![Segment::new("master"), Segment::styled(" +", bold)];
+        assert_eq!(format!("{}",
+                           format_for_module(segments,
+                                             &options,
+                                             Neighbours::default(),
+                                             Side::Left,
+                                             Shell::Zsh)),
+                   " master%{\x1B[1m%} +%{\x1B[0m%} ");
+    }
+
+    #[test]
+    fn test_format_for_module_fish() {
+        const CONTENT: &'static str = "Hello";
+        const PADDING: &'static str = " ";
+        const SEPARATOR: &'static str = ">";
+
+        let options = ModuleOptions {
+            output: None,
+            padding_left: PADDING.to_string(),
+            padding_right: PADDING.to_string(),
+            separator: SEPARATOR.to_string(),
+            style: ModuleStyle {
+                background: Some(Color::Blue),
+                foreground: Some(Color::White),
+                text_properties: Some(Style::default().bold()),
+            },
+            ..ModuleOptions::default()
+        };
+
+        // Fish doesn't need (or understand) any length escape
+        // sequences, so only the color codes should be present
+        let formatted_string = format_for_module(vec![Segment::new(CONTENT)],
+                                                 &options,
+                                                 Neighbours::default(),
+                                                 Side::Left,
+                                                 Shell::Fish);
+        assert_eq!(format!("\x1B[1;44;37m{}{}{}\x1B[0m\x1B[1;34m{}\x1B[0m",
+                           PADDING,
+                           CONTENT,
+                           PADDING,
+                           SEPARATOR),
+                   format!("{}", formatted_string));
+
+        // The separator takes on the next module's background
+        let neighbours = Neighbours {
+            prev_bg: None,
+            next_bg: Some(Color::Green),
+        };
+        let formatted_string =
+            format_for_module(vec![Segment::new(CONTENT)],
+                              &options,
+                              neighbours,
+                              Side::Left,
+                              Shell::Fish);
+        assert_eq!(format!("\x1B[1;44;37m{}{}{}\x1B[0m\x1B[1;42;34m{}\x1B[0m",
+                           PADDING,
+                           CONTENT,
+                           PADDING,
+                           SEPARATOR),
+                   format!("{}", formatted_string));
+    }
+
+    #[test]
+    fn test_format_for_module_separators() {
+        let options = ModuleOptions {
+            padding_left: String::new(),
+            padding_right: String::new(),
+            separator: String::from(">"),
+            separator_left: String::from("<"),
+            separator_thin: Some(String::from("|")),
+            style: ModuleStyle {
+                background: Some(Color::Blue),
+                foreground: Some(Color::White),
+                text_properties: None,
+            },
+            ..ModuleOptions::default()
+        };
+        let format = |neighbours| {
+            format!("{}",
+                    format_for_module(vec![Segment::new("x")],
+                                      &options,
+                                      neighbours,
+                                      Side::Left,
+                                      Shell::Fish))
+        };
+
+        // The left separator is drawn on top of the previous module
+        let neighbours = Neighbours {
+            prev_bg: Some(Color::Red),
+            next_bg: Some(Color::Green),
+        };
+        assert_eq!(format(neighbours),
+                   "\x1B[41;34m<\x1B[0m\x1B[44;37mx\x1B[0m\x1B[42;34m>\x1B[0m");
+
+        // Modules of the same color are split by the thin separator
+        let neighbours = Neighbours {
+            prev_bg: None,
+            next_bg: Some(Color::Blue),
+        };
+        assert_eq!(format(neighbours),
+                   "\x1B[34m<\x1B[0m\x1B[44;37mx\x1B[0m\x1B[44;37m|\x1B[0m");
+
+        // ...unless there's no thin separator to use
+        let options = ModuleOptions {
+            separator_thin: None,
+            ..options
+        };
+        assert_eq!(format!("{}",
+                           format_for_module(vec![Segment::new("x")],
+                                             &options,
+                                             neighbours,
+                                             Side::Left,
+                                             Shell::Fish)),
+                   "\x1B[34m<\x1B[0m\x1B[44;37mx\x1B[0m\x1B[44;34m>\x1B[0m");
+
+        // The default separators point the other way on the right
+        let options = ModuleOptions {
+            padding_left: String::new(),
+            padding_right: String::new(),
+            ..ModuleOptions::default()
+        };
+        assert_eq!(format!("{}",
+                           format_for_module(vec![Segment::new("x")],
+                                             &options,
+                                             Neighbours::default(),
+                                             Side::Right,
+                                             Shell::Fish)),
+                   "x");
+    }
+
+    #[test]
+    fn test_draw() {
+        let module = |text: &str, bg: Option<Color>| {
+            FormatResult {
+                output: Some(vec![Segment::new(text)]),
+                options: ModuleOptions {
+                    padding_left: String::new(),
+                    padding_right: String::new(),
+                    separator: String::from(">"),
+                    separator_left: String::from("<"),
+                    separator_right: String::from("]"),
+                    style: ModuleStyle {
+                        background: bg,
+                        ..ModuleStyle::default()
+                    },
+                    ..ModuleOptions::default()
+                },
+            }
+        };
+
+        // Hidden modules don't count as neighbours, so "a" and "b"
+        // are drawn right next to each other
+        let results = vec![module("a", Some(Color::Red)),
+                           FormatResult::default(),
+                           module("b", Some(Color::Blue))];
+        assert_eq!(draw(results, Side::Left, Shell::Fish, None),
+                   "\x1B[31m<\x1B[0m\x1B[41ma\x1B[0m\x1B[44;31m>\x1B[0m\
+                    \x1B[41;34m<\x1B[0m\x1B[44mb\x1B[0m\x1B[34m>\x1B[0m");
+
+        // On the right, `separator_right` goes in front of each module
+        // and takes on the background of the module before it
+        let results = vec![module("a", Some(Color::Red)), module("b", Some(Color::Blue))];
+        assert_eq!(draw(results, Side::Right, Shell::Fish, None),
+                   "\x1B[31m]\x1B[0m\x1B[41ma\x1B[0m\x1B[44;31m<\x1B[0m\
+                    \x1B[41;34m]\x1B[0m\x1B[44mb\x1B[0m\x1B[34m<\x1B[0m");
+
+        assert_eq!(draw(Vec::new(), Side::Left, Shell::Bash, None), "");
+    }
+
+    #[test]
+    fn test_fit() {
+        let module = |text: &str, priority: i64, min_width: Option<usize>| {
+            (vec![Segment::new(text)],
+             ModuleOptions {
+                 padding_left: String::new(),
+                 padding_right: String::from(" "),
+                 priority: priority,
+                 min_width: min_width,
+                 ..ModuleOptions::default()
+             })
+        };
+        let texts = |modules: &[(Vec<Segment>, ModuleOptions)]| -> Vec<String> {
+            modules
+                .iter()
+                .map(|module| module.0.iter().map(|segment| segment.text.clone()).collect())
+                .collect()
+        };
+
+        // The lowest priority goes first
+        let mut modules = vec![module("aa", 1, None), module("bb", 0, None), module("cc", 2, None)];
+        fit(&mut modules, Side::Left, 6);
+        assert_eq!(texts(&modules), vec!["aa", "cc"]);
+        fit(&mut modules, Side::Left, 5);
+        assert_eq!(texts(&modules), vec!["cc"]);
+
+        // Ties are broken by dropping whatever's furthest from the
+        // cursor
+        let mut modules = vec![module("aa", 0, None), module("bb", 0, None)];
+        fit(&mut modules, Side::Left, 3);
+        assert_eq!(texts(&modules), vec!["bb"]);
+        let mut modules = vec![module("aa", 0, None), module("bb", 0, None)];
+        fit(&mut modules, Side::Right, 3);
+        assert_eq!(texts(&modules), vec!["aa"]);
+
+        // Modules with a minimum width are shrunk before being dropped
+        let mut modules = vec![module("/usr/local/bin", 0, Some(4)), module("$", 1, None)];
+        fit(&mut modules, Side::Left, 10);
+        assert_eq!(texts(&modules), vec!["…al/bin", "$"]);
+        fit(&mut modules, Side::Left, 7);
+        assert_eq!(texts(&modules), vec!["…bin", "$"]);
+        fit(&mut modules, Side::Left, 6);
+        assert_eq!(texts(&modules), vec!["$"]);
+
+        // Everything fits already
+        let mut modules = vec![module("aa", 0, None)];
+        fit(&mut modules, Side::Left, 3);
+        assert_eq!(texts(&modules), vec!["aa"]);
+    }
+
+    #[test]
+    fn test_shrink() {
+        let red = ModuleStyle {
+            foreground: Some(Color::Red),
+            ..ModuleStyle::default()
+        };
+        let segments = || vec![Segment::new("ab"), Segment::styled("cde", red)];
+
+        assert_eq!(shrink(segments(), 5), segments());
+        assert_eq!(shrink(segments(), 4),
+                   vec![Segment::new("…"), Segment::styled("cde", red)]);
+        assert_eq!(shrink(segments(), 3),
+                   vec![Segment::styled("…", red), Segment::styled("de", red)]);
+        assert_eq!(shrink(segments(), 1), vec![Segment::styled("…", red)]);
+        assert_eq!(shrink(segments(), 0), Vec::new());
+
+        // Wide characters take up two columns each
+        assert_eq!(shrink(vec![Segment::new("日本語")], 4),
+                   vec![Segment::new("…"), Segment::new("語")]);
+    }
+}
@@ -2,16 +2,27 @@ extern crate ansi_term;
 extern crate clap;
 extern crate config;
 extern crate git2;
+extern crate libc;
+extern crate toml;
+extern crate unicode_width;
 
+#[cfg(test)]
+extern crate tempdir;
+
+use std::process;
 use std::str::FromStr;
 
-use ansi_term::{ANSIString, ANSIStrings, Color};
-use clap::{App, Arg, Shell};
-use config::{Config, File, FileFormat};
+use clap::{App, Arg, ArgMatches, Shell, SubCommand};
+use config::{Config, Value};
 
+#[cfg(unix)]
+mod daemon;
+mod init;
+mod layout;
 mod utils;
 mod modules;
 
+use layout::{Side, LINE_BREAK};
 use utils::*;
 use modules::*;
 
@@ -33,78 +44,346 @@ fn main() {
                  .long("config")
                  .value_name("FILE")
                  .help("Location of configuration file")
-                 .takes_value(true))
+                 .takes_value(true)
+                 .global(true))
         .arg(Arg::with_name("shell")
                  .long("shell")
                  .value_name("SHELL")
                  .takes_value(true)
                  .possible_values(&["bash", "zsh", "fish", "powershell"]))
+        .arg(Arg::with_name("jobs")
+                 .short("j")
+                 .long("jobs")
+                 .value_name("COUNT")
+                 .help("Number of jobs running in the background")
+                 .takes_value(true))
+        .arg(Arg::with_name("duration")
+                 .short("d")
+                 .long("duration")
+                 .value_name("MILLISECONDS")
+                 .help("Time taken by the last-executed command")
+                 .takes_value(true))
+        .arg(Arg::with_name("width")
+                 .long("width")
+                 .value_name("COLUMNS")
+                 .help("Width of the terminal")
+                 .takes_value(true))
+        .arg(Arg::with_name("side")
+                 .long("side")
+                 .value_name("SIDE")
+                 .help("Side of the line to draw the prompt for")
+                 .takes_value(true)
+                 .possible_values(&["left", "right"]))
+        .arg(Arg::with_name("socket")
+                 .long("socket")
+                 .value_name("PATH")
+                 .help("Location of the daemon's socket")
+                 .takes_value(true)
+                 .global(true))
+        .subcommand(SubCommand::with_name("daemon")
+                        .about("Keeps running in the background to draw prompts faster"))
+        .subcommand(SubCommand::with_name("init")
+                        .about("Prints the code needed to use contrail as your prompt")
+                        .arg(Arg::with_name("shell")
+                                 .value_name("SHELL")
+                                 .required(true)
+                                 .possible_values(&["bash", "zsh", "fish", "powershell"])))
         .get_matches();
-    
-    let mut c = Config::new();
 
-    if let Some(f) = matches.value_of("config") {
-        c.merge(File::new(f, FileFormat::Toml).required(false))
-            .expect("Failed to merge in config file!");
+    if let Some(matches) = matches.subcommand_matches("init") {
+        // clap guarantees that the shell is present and valid
+        let shell = Shell::from_str(matches.value_of("shell").unwrap())
+            .expect("Invalid shell type passed!");
+        print!("{}", init::init_script(shell, matches.value_of("config")));
+        return;
     }
 
-    let exit_code = matches
-        .value_of("exit_code")
-        .unwrap_or("255")
-        .parse::<u8>()
-        .expect("Exit code passed as argument was not a u8!");
+    if let Some(matches) = matches.subcommand_matches("daemon") {
+        run_daemon(matches);
+        return;
+    }
 
-    let shell = if let Some(s) = matches.value_of("shell") {
-        // This shouldn't panic, clap will enforce that the correct
-        // shell types were passed at runtime
-        Shell::from_str(s).expect("Invalid shell type passed!")
-    } else {
-        Shell::Bash
+    let mut args = PromptArgs {
+        config: matches.value_of("config").map(String::from),
+        shell: matches.value_of("shell").map(String::from),
+        exit_code: matches.value_of("exit_code").map(String::from),
+        jobs: matches.value_of("jobs").map(String::from),
+        duration: matches.value_of("duration").map(String::from),
+        side: matches.value_of("side").map(String::from),
+        width: matches.value_of("width").map(String::from),
     };
+    let env = Environment::current();
+
+    // The daemon isn't attached to any terminal, so the width has to
+    // be measured here
+    if args.width.is_none() && env.var("COLUMNS").is_none() {
+        args.width = terminal_width().map(|width| width.to_string());
+    }
 
-    let module_names: Vec<String> = if let Some(arr) = ref_get_array("global.modules", &c) {
-        // into_str() always succeeds, so it's safe to call unwrap()
-        arr.into_iter()
-            .map(|m| m.into_str().unwrap())
-            .rev()
-            .collect()
+    // Let the daemon do the work if it's running. If it isn't (or
+    // something goes wrong while talking to it), the prompt is
+    // rendered right here instead.
+    if render_with_daemon(&matches, &args, &env) {
+        return;
+    }
+
+    // Nothing from here on is allowed to crash. Errors are collected
+    // and reported once the prompt has been printed, so that a typo
+    // in the config never leaves the user without a prompt.
+    let mut errors: Vec<Error> = Vec::new();
+
+    let c = if let Some(ref f) = args.config {
+        load_config(f).unwrap_or_else(|err| {
+                                          errors.push(err);
+                                          Config::new()
+                                      })
     } else {
-        // The actual order is "cwd", "git", "prompt", but we must
-        // manually reverse it
-        vec![String::from("prompt"),
-             String::from("git"),
-             String::from("cwd")]
+        Config::new()
     };
 
-    let mut formatted_strings: Vec<ANSIString<'static>> = Vec::new();
-
-    let mut next_bg: Option<Color> = None;
-    for name in &module_names {
-        let result = match name.as_ref() {
-            // If errors are encountered, just crash and display the
-            // error message
-            "cwd" => format_cwd(&c, next_bg, shell).unwrap_or_else(|err| panic!("{}", err)),
-            "exit_code" => {
-                format_exit_code(&c, exit_code, next_bg, shell).unwrap_or_else(|err| {
-                                                                                   panic!("{}", err)
-                                                                               })
-            }
-            "git" => format_git(&c, next_bg, shell).unwrap_or_else(|err| panic!("{}", err)),
-            "prompt" => {
-                format_prompt(&c, exit_code, next_bg, shell).unwrap_or_else(|err| panic!("{}", err))
+    print!("{}", render_prompt(&args, &c, &env, None, &mut errors));
+
+    let log_file = c.get("global.error_log").and_then(Value::into_str);
+    report_errors(&errors, log_file.as_ref().map(String::as_ref));
+}
+
+/// Everything passed on the command line that changes how the prompt
+/// looks, exactly as it was passed
+#[derive(Debug, Default, PartialEq)]
+pub struct PromptArgs {
+    pub config: Option<String>,
+    pub shell: Option<String>,
+    pub exit_code: Option<String>,
+    pub jobs: Option<String>,
+    pub duration: Option<String>,
+    pub side: Option<String>,
+    pub width: Option<String>,
+}
+
+/// Renders the prompt described by `args`, using the config file `c`.
+/// `git_cache` is only given when running as a daemon.
+///
+/// Nothing in here is allowed to crash: anything that goes wrong is
+/// added to `errors` instead.
+pub fn render_prompt(args: &PromptArgs,
+                     c: &Config,
+                     env: &Environment,
+                     git_cache: Option<&GitCache>,
+                     errors: &mut Vec<Error>)
+                     -> String {
+    let exit_code = parse_arg(&args.exit_code, "exit_code", 255u8, errors);
+    let jobs = parse_arg(&args.jobs, "jobs", 0u32, errors);
+    let duration = parse_arg(&args.duration, "duration", 0u64, errors);
+
+    // clap only lets valid shell types through on the command line,
+    // but the daemon can be sent anything
+    let shell = parse_arg(&args.shell, "shell", Shell::Bash, errors);
+    let side = parse_arg(&args.side, "side", Side::Left, errors);
+
+    let modules_key = match side {
+        Side::Left => "global.modules",
+        Side::Right => "global.right_modules",
+    };
+    let module_names: Vec<String> = if let Some(arr) = ref_get_array(modules_key, c) {
+        let mut names = Vec::new();
+        for m in arr {
+            if let Value::String(s) = m {
+                names.push(s);
+            } else {
+                errors.push(Error::new(ErrorKind::InvalidTypeInConfig,
+                                       &format!("expected module name, got: {:?}", m)));
             }
-            s => format_generic(s, &c, next_bg, shell).unwrap_or_else(|err| panic!("{}", err)),
-        };
+        }
+        names
+    } else if side == Side::Left {
+        vec![String::from("cwd"),
+             String::from("git"),
+             String::from("prompt")]
+    } else {
+        // Nothing is shown on the right unless asked for
+        Vec::new()
+    };
+
+    let ctx = Context {
+        config: c,
+        exit_code: exit_code,
+        jobs: jobs,
+        duration: duration,
+        env: env,
+        git_cache: git_cache,
+    };
+    let registry = Registry::default();
 
-        // Only update the next_bg if we successfully formatted. The
-        // Vec was reversed earlier (to make it possible for a module
-        // to know the "next" module's background), so we must insert
-        // at the beginning of the resultant formatted strings Vec
-        if let Some(r) = result.output {
-            next_bg = result.next_bg;
-            formatted_strings.insert(0, r);
+    // Failing modules are replaced by an error indicator, unless the
+    // user would rather they disappear
+    let show_errors = match c.get("global.on_error") {
+        None => true,
+        Some(Value::String(ref s)) if s == "show" => true,
+        Some(Value::String(ref s)) if s == "skip" => false,
+        Some(val) => {
+            errors.push(Error::new(ErrorKind::NoSuchMatchInConfig,
+                                   &format!("expected \"show\" or \"skip\", got: {:?}", val)));
+            true
         }
+    };
+
+    // Modules are dropped (or shrunk) until each line fits in a part
+    // of the terminal's width, if it's known
+    let max_width_ratio = match c.get("global.max_width") {
+        None => 1.0,
+        Some(Value::Float(ratio)) if ratio > 0.0 && ratio <= 1.0 => ratio,
+        Some(Value::Integer(1)) => 1.0,
+        Some(val) => {
+            errors.push(Error::new(ErrorKind::InvalidTypeInConfig,
+                                   &format!("expected a fraction between 0 and 1, got: {:?}",
+                                            val)));
+            1.0
+        }
+    };
+    let columns = match parse_arg(&args.width, "width", 0usize, errors) {
+        0 => env.var("COLUMNS").and_then(|columns| columns.parse().ok()),
+        columns => Some(columns),
+    };
+    // The scripts from `contrail init` add a space after the prompt,
+    // and the cursor needs a column too, or the line would wrap
+    let max_width = columns.map(|columns| {
+                                    let max_width = (columns as f64 * max_width_ratio) as usize;
+                                    max_width.min(columns.saturating_sub(2))
+                                });
+
+    // Every module is rendered at once. Only then can they be drawn,
+    // since separators depend on the modules on either side of them.
+    let rendered_names: Vec<String> = module_names
+        .iter()
+        .filter(|name| *name != LINE_BREAK)
+        .cloned()
+        .collect();
+    let mut results = registry
+        .render_all(&rendered_names, &ctx)
+        .into_iter()
+        .map(|result| {
+            result.unwrap_or_else(|err| {
+                errors.push(err);
+                if show_errors {
+                    format_fallback(&ctx)
+                } else {
+                    FormatResult::default()
+                }
+            })
+        });
+
+    // Each line is drawn on its own, so that no separator reaches
+    // across a line break
+    let lines: Vec<String> = module_names
+        .split(|name| name == LINE_BREAK)
+        .map(|line| {
+                 layout::draw(results.by_ref().take(line.len()).collect(),
+                              side,
+                              shell,
+                              max_width)
+             })
+        .collect();
+
+    lines.join("\n")
+}
+
+/// Parses the value passed for the argument `name`, or returns
+/// `default` if it wasn't passed. If the value can't be parsed, an
+/// `Error` is added to `errors` and `default` is returned as well.
+fn parse_arg<T: FromStr>(value: &Option<String>, name: &str, default: T, errors: &mut Vec<Error>) -> T {
+    if let Some(ref s) = *value {
+        s.parse().unwrap_or_else(|_| {
+            errors.push(Error::new(ErrorKind::InvalidArgument,
+                                   &format!("invalid value for --{}: {:?}", name, s)));
+            default
+        })
+    } else {
+        default
+    }
+}
+
+#[cfg(unix)]
+fn run_daemon(matches: &ArgMatches) {
+    let socket = daemon::socket_path(matches.value_of("socket"));
+    if let Err(err) = daemon::run(&socket) {
+        eprintln!("contrail: {}", err);
+        process::exit(1);
     }
+}
 
-    print!("{}", ANSIStrings(formatted_strings.as_slice()));
+#[cfg(not(unix))]
+fn run_daemon(_: &ArgMatches) {
+    eprintln!("contrail: the daemon is only available on Unix");
+    process::exit(1);
+}
+
+/// Asks the daemon to render the prompt, and prints it.
+///
+/// Returns `false` if the daemon isn't running or didn't answer, in
+/// which case nothing has been printed.
+#[cfg(unix)]
+fn render_with_daemon(matches: &ArgMatches, args: &PromptArgs, env: &Environment) -> bool {
+    let socket = daemon::socket_path(matches.value_of("socket"));
+    if let Some(response) = daemon::request(&socket, args, env) {
+        print!("{}", response.prompt);
+        for err in &response.errors {
+            eprintln!("contrail: {}", err);
+        }
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(not(unix))]
+fn render_with_daemon(_: &ArgMatches, _: &PromptArgs, _: &Environment) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prompt_line_break() {
+        let mut c = Config::new();
+        c.set("global.modules", vec!["jobs", "newline", "prompt"])
+            .unwrap();
+        c.set("modules.jobs.separator", ">").unwrap();
+        c.set("modules.jobs.style.background", "blue").unwrap();
+        c.set("modules.prompt.style_success.background", "green")
+            .unwrap();
+
+        let mut args = PromptArgs {
+            shell: Some(String::from("fish")),
+            exit_code: Some(String::from("0")),
+            jobs: Some(String::from("1")),
+            ..PromptArgs::default()
+        };
+
+        // The separator before the line break doesn't take on the
+        // background of the next line
+        let mut errors = Vec::new();
+        assert_eq!(render_prompt(&args, &c, &Environment::default(), None, &mut errors),
+                   "\x1B[44m 1 \x1B[0m\x1B[34m>\x1B[0m\n\x1B[42m $ \x1B[0m");
+        assert!(errors.is_empty());
+
+        // Each line is fitted to the width on its own
+        args.width = Some(String::from("6"));
+        c.set("global.max_width", 0.5).unwrap();
+        assert_eq!(render_prompt(&args, &c, &Environment::default(), None, &mut errors),
+                   "\n\x1B[42m $ \x1B[0m");
+        assert!(errors.is_empty());
+
+        // Even the whole width leaves room for the space after the
+        // prompt and the cursor
+        c.set("global.max_width", 1.0).unwrap();
+        args.width = Some(String::from("5"));
+        assert_eq!(render_prompt(&args, &c, &Environment::default(), None, &mut errors),
+                   "\n\x1B[42m $ \x1B[0m");
+        args.width = Some(String::from("6"));
+        assert_eq!(render_prompt(&args, &c, &Environment::default(), None, &mut errors),
+                   "\x1B[44m 1 \x1B[0m\x1B[34m>\x1B[0m\n\x1B[42m $ \x1B[0m");
+        assert!(errors.is_empty());
+    }
 }
@@ -1,24 +1,58 @@
-use std::env;
-use std::iter::FromIterator;
-use std::path::PathBuf;
+use std::cmp::Reverse;
+#[cfg(unix)]
+use std::ffi::CString;
+use std::ffi::OsString;
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
 
-use ansi_term::Color;
+use ansi_term::Style;
 use config::{Config, Value};
-use clap::Shell;
+#[cfg(unix)]
+use libc;
 
 use utils::{Error, ErrorKind, FormatResult};
 
-use modules;
+use modules::{self, Context, Environment, Module, ModuleStyle, Segment};
+use modules::git::discover_repository;
+
+/// Shows the current working directory.
+pub struct Cwd;
+
+impl Module for Cwd {
+    fn name(&self) -> &str {
+        "cwd"
+    }
+
+    fn render(&self, ctx: &Context) -> Result<FormatResult, Error> {
+        format_cwd(ctx)
+    }
+}
+
+/// How the directories leading up to the current one are shown
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PathStyle {
+    /// Every directory is shown in full
+    Full,
+    /// Every directory is cut down to its first few characters, like
+    /// fish does
+    Fish(usize),
+    /// Every directory is cut down to the shortest prefix that none of
+    /// the directories next to it start with
+    Unique,
+}
 
 /// Formats the current working directory using whatever options are
 /// present in the config file provided.
 ///
 /// Returns an `Error` if it encounters any errors while parsing the
 /// config file.
-pub fn format_cwd(c: &Config, next_bg: Option<Color>, shell: Shell) -> Result<FormatResult, Error> {
-    let options = modules::read_options("cwd", c)?;
+fn format_cwd(ctx: &Context) -> Result<FormatResult, Error> {
+    let c = ctx.config;
+    let mut options = modules::read_options("cwd", c)?;
 
-    let mut cwd = if let Ok(pwd) = env::var("PWD") {
+    let mut cwd = if let Some(pwd) = ctx.env.var("PWD") {
         // We prioritize using $PWD because the user doesn't expect to
         // see the absolute path, but rather the symlinks. This is
         // consistent with other powerline-like implementations.
@@ -30,17 +64,50 @@ pub fn format_cwd(c: &Config, next_bg: Option<Color>, shell: Shell) -> Result<Fo
         // Fail silently if the "current directory" turns out to not
         // exist. We don't want to spew a bunch of error messages in
         // this circumstance, and this should be opaque to the user.
-        env::current_dir().unwrap_or_default()
+        ctx.env.current_dir.clone().unwrap_or_default()
     };
 
-    // Truncate leading instance of $HOME to just "~/"
-    if let Ok(home) = env::var("HOME") {
-        if let Ok(stripped_cwd) = cwd.clone().strip_prefix(&home) {
-            cwd = PathBuf::from("~").join(stripped_cwd);
+    // Inside a repository, the path can start at the repository's
+    // root instead
+    let repo = if modules::read_bool("modules.cwd.repo_relative", false, c)? {
+        ctx.env
+            .current_dir
+            .as_ref()
+            .and_then(|dir| repo_relative_path(dir, ctx.env))
+    } else {
+        None
+    };
+
+    // Directories are abbreviated based on what's next to them on
+    // disk, so the actual location has to be kept around
+    let mut real_cwd = cwd.clone();
+
+    // Number of components at the start of the path that were put
+    // there by a substitution, and shouldn't be abbreviated
+    let mut substituted = 0;
+
+    if let Some((_, ref relative_path)) = repo {
+        real_cwd = ctx.env.current_dir.clone().unwrap_or_default();
+        cwd = relative_path.clone();
+    } else {
+        let substitutions = read_substitutions(ctx)?;
+        let real_dir = ctx.env
+            .current_dir
+            .as_ref()
+            .and_then(|dir| dir.canonicalize().ok());
+        if let Some((path, real_path, len)) = substitute(&cwd, real_dir, &substitutions) {
+            cwd = path;
+            real_cwd = real_path;
+            substituted = len;
         }
     }
 
-    // Truncate extra long paths to a certain depth
+    let path_style = read_path_style(c)?;
+    if path_style != PathStyle::Full {
+        cwd = abbreviate(&cwd, &real_cwd, path_style, substituted);
+    }
+
+    // Extra long paths are truncated to a certain depth
     let depth = cwd.components().count();
     let max_depth: usize = if let Some(val) = c.get("modules.cwd.max_depth") {
         match val {
@@ -59,26 +126,748 @@ pub fn format_cwd(c: &Config, next_bg: Option<Color>, shell: Shell) -> Result<Fo
                                       &format!("expected usize, got: {:?}", val)));
             }
         }
-    } else {
-        // Default maximum depth is 4
+    } else if path_style == PathStyle::Full {
+        // Default maximum depth is 4, not counting the repository's
+        // name
         4
+    } else {
+        // Abbreviated paths are short enough as they are
+        usize::MAX
+    };
+
+    // Every directory gets a segment of its own when they're split
+    // up, with the current one styled differently
+    let path_separator = if c.get("modules.cwd.path_separator").is_some() {
+        Some(modules::read_string("modules.cwd.path_separator", "", c)?)
+    } else {
+        None
     };
+    let style_current = modules::read_style("modules.cwd.style_current", c)?
+        .or(&ModuleStyle {
+                 text_properties: Some(Style::new().bold()),
+                 ..ModuleStyle::default()
+             });
 
-    if depth > max_depth {
-        let iter = cwd.clone();
-        let iter = iter.iter();
+    let repo = match repo {
+        Some((name, _)) => {
+            let style_repo = modules::read_style("modules.cwd.style_repo", c)?
+                .or(&ModuleStyle {
+                         text_properties: Some(Style::new().bold()),
+                         ..ModuleStyle::default()
+                     });
+            Some(Segment::styled(name, style_repo))
+        }
+        None => None,
+    };
+
+    let ellipsis = modules::read_string("modules.cwd.ellipsis", "...", c)?;
+    let max_length: Option<usize> = match c.get("modules.cwd.max_length") {
+        None => None,
+        Some(Value::Integer(n)) if n >= 0 => Some(n as usize),
+        Some(val) => {
+            return Err(Error::new(ErrorKind::InvalidTypeInConfig,
+                                  &format!("expected usize, got: {:?}", val)));
+        }
+    };
+
+    // Directories are cut off the start of the path until there are
+    // no more than `max_depth` of them, and then until the path fits
+    // in `max_length` columns. The current directory always stays.
+    let components: Vec<Component> = cwd.components().collect();
+    let segments_after = |skip: usize| {
+        let path = if skip == 0 {
+            cwd.clone()
+        } else {
+            let mut path = PathBuf::from(&ellipsis);
+            path.extend(&components[skip..]);
+            path
+        };
+        path_segments(repo.as_ref(), &path, path_separator.as_ref(), style_current)
+    };
+
+    let mut skip = depth.saturating_sub(max_depth);
+    let mut segments = segments_after(skip);
+    if let Some(max_length) = max_length {
+        while skip + 1 < components.len() && modules::segments_width(&segments) > max_length {
+            // The ellipsis can be wider than a single directory, like
+            // "~", that it replaces
+            skip += 1;
+            let shorter = segments_after(skip);
+            if modules::segments_width(&shorter) < modules::segments_width(&segments) {
+                segments = shorter;
+            }
+        }
+    }
 
-        cwd = PathBuf::from("...");
-        cwd.push(PathBuf::from_iter(iter.skip(depth - max_depth)));
+    // Warn about directories that can't be used as usual, which are
+    // otherwise only noticed once commands start failing in them
+    let state = dir_state(ctx.env.current_dir.as_deref(), is_writable);
+    if let Some((state, default_symbol)) = state {
+        let symbol = modules::read_string(&format!("modules.cwd.symbol_{}", state), default_symbol, c)?;
+        let style = modules::read_style(&format!("modules.cwd.style_{}", state), c)?;
+        options.style = style.or(&options.style);
+        segments.push(Segment::new(symbol));
     }
 
     let format_result = FormatResult {
-        output: Some(modules::format_for_module(format!("{}", cwd.display()),
-                                                &options,
-                                                next_bg,
-                                                shell)),
-        next_bg: options.style.background,
+        output: Some(segments),
+        options: options,
     };
 
     Ok(format_result)
 }
+
+/// Splits `cwd` up into segments, with the name of the repository it's
+/// in (if any) in front. If there's a `path_separator`, every
+/// directory gets a segment of its own, with the current one styled
+/// with `style_current`.
+fn path_segments(repo: Option<&Segment>,
+                 cwd: &Path,
+                 path_separator: Option<&String>,
+                 style_current: ModuleStyle)
+                 -> Vec<Segment> {
+    let mut segments = Vec::new();
+    if let Some(repo) = repo {
+        segments.push(Segment::styled(repo.text.as_str(), repo.style));
+    }
+
+    if let Some(path_separator) = path_separator {
+        for component in cwd.components() {
+            if !segments.is_empty() {
+                segments.push(Segment::new(path_separator.as_str()));
+            }
+            segments.push(Segment::new(component.as_os_str().to_string_lossy()));
+        }
+
+        if let Some(current) = segments.last_mut() {
+            current.style = style_current.or(&current.style);
+        }
+    } else if segments.is_empty() {
+        segments.push(Segment::new(format!("{}", cwd.display())));
+    } else if cwd.components().next().is_some() {
+        segments.push(Segment::new(format!("/{}", cwd.display())));
+    }
+
+    segments
+}
+
+/// Picks what to warn about the working directory `dir`, checking
+/// whether it can be written to with `is_writable`.
+///
+/// Returns the name of the state and its default symbol, or `None` if
+/// the directory can be used as usual.
+fn dir_state(dir: Option<&Path>,
+             is_writable: fn(&Path) -> bool)
+             -> Option<(&'static str, &'static str)> {
+    match dir {
+        Some(dir) if dir.is_dir() => {
+            if is_writable(dir) {
+                None
+            } else {
+                Some(("readonly", " 🔒"))
+            }
+        }
+        _ => Some(("missing", " ⚠")),
+    }
+}
+
+/// Checks whether files can be created in `dir`.
+#[cfg(unix)]
+fn is_writable(dir: &Path) -> bool {
+    match CString::new(dir.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => true,
+    }
+}
+
+/// Checks whether files can be created in `dir`.
+#[cfg(not(unix))]
+fn is_writable(dir: &Path) -> bool {
+    fs::metadata(dir)
+        .map(|metadata| !metadata.permissions().readonly())
+        .unwrap_or(true)
+}
+
+/// Finds the repository that `dir` is in, using the same rules as the
+/// "git" module.
+///
+/// Returns the name of the repository along with the path of `dir`
+/// relative to the repository's root, or `None` if `dir` isn't inside
+/// a repository's working tree.
+fn repo_relative_path(dir: &Path, env: &Environment) -> Option<(String, PathBuf)> {
+    let repo = discover_repository(dir, env)?;
+    let root = repo.workdir()?.canonicalize().ok()?;
+    let relative_path = dir.canonicalize()
+        .ok()?
+        .strip_prefix(&root)
+        .ok()?
+        .to_path_buf();
+    let name = root.file_name()?.to_string_lossy().into_owned();
+
+    Some((name, relative_path))
+}
+
+/// Replaces the start of the path, like `$HOME` with `~`
+#[derive(Debug)]
+struct Substitution {
+    from: PathBuf,
+    to: PathBuf,
+}
+
+/// Reads `modules.cwd.substitutions`, and adds the one for `$HOME`.
+/// Substitutions that replace more of the path come first.
+///
+/// In the config file they're usually a table that maps each path to
+/// what it's replaced with, but `load_config` turns that into the
+/// array of tables with a `from` and a `to` that's read here.
+///
+/// Returns an `Error` if they aren't an array, or if any of them
+/// doesn't have both a `from` and a `to`.
+fn read_substitutions(ctx: &Context) -> Result<Vec<Substitution>, Error> {
+    let mut substitutions = Vec::new();
+    match ctx.config.get("modules.cwd.substitutions") {
+        None => {}
+        Some(Value::Array(vals)) => {
+            for val in vals {
+                substitutions.push(read_substitution(val)?);
+            }
+        }
+        Some(val) => {
+            return Err(Error::new(ErrorKind::InvalidTypeInConfig,
+                                  &format!("expected array, got: {:?}", val)));
+        }
+    }
+
+    // Truncate leading instance of $HOME to just "~/"
+    if let Some(home) = ctx.env.var("HOME") {
+        substitutions.push(Substitution {
+                               from: PathBuf::from(home),
+                               to: PathBuf::from("~"),
+                           });
+    }
+
+    // The sort is stable, so the user's substitutions win over $HOME
+    // when they replace just as much
+    substitutions.sort_by_key(|substitution| Reverse(substitution.from.components().count()));
+    Ok(substitutions)
+}
+
+/// Reads a substitution given as a table with a `from` and a `to`.
+///
+/// Returns an `Error` if either of them is missing.
+fn read_substitution(val: Value) -> Result<Substitution, Error> {
+    let (from, to) = match val {
+        Value::Table(ref table) => {
+            (table.get("from").cloned().and_then(Value::into_str),
+             table.get("to").cloned().and_then(Value::into_str))
+        }
+        _ => (None, None),
+    };
+
+    if let (Some(from), Some(to)) = (from, to) {
+        Ok(Substitution {
+               from: PathBuf::from(from),
+               to: PathBuf::from(to),
+           })
+    } else {
+        Err(Error::new(ErrorKind::InvalidTypeInConfig,
+                       &format!("expected table with \"from\" and \"to\", got: {:?}", val)))
+    }
+}
+
+/// Replaces the start of `cwd` using the first of `substitutions` that
+/// fits. `real_dir` is where `cwd` actually is on disk, which is
+/// matched too in case `cwd` goes through a symlink.
+///
+/// Returns the new path, the path on disk that it matches, and the
+/// number of components the substitution takes up.
+fn substitute(cwd: &Path,
+              real_dir: Option<PathBuf>,
+              substitutions: &[Substitution])
+              -> Option<(PathBuf, PathBuf, usize)> {
+    for substitution in substitutions {
+        let (rest, real_path) = if let Ok(rest) = cwd.strip_prefix(&substitution.from) {
+            (rest.to_path_buf(), cwd.to_path_buf())
+        } else if let Some(ref real_dir) = real_dir {
+            let from = substitution
+                .from
+                .canonicalize()
+                .unwrap_or_else(|_| substitution.from.clone());
+            match real_dir.strip_prefix(&from) {
+                Ok(rest) => (rest.to_path_buf(), real_dir.clone()),
+                Err(_) => continue,
+            }
+        } else {
+            continue;
+        };
+
+        let mut path = substitution.to.clone();
+        if rest.components().next().is_some() {
+            path.push(rest);
+        }
+        return Some((path, real_path, substitution.to.components().count()));
+    }
+
+    None
+}
+
+/// Reads `modules.cwd.path_style`, along with `modules.cwd.dir_length`
+/// for the "fish" style.
+///
+/// Returns an `Error` if either of them is invalid.
+fn read_path_style(c: &Config) -> Result<PathStyle, Error> {
+    let style = match c.get("modules.cwd.path_style") {
+        None => return Ok(PathStyle::Full),
+        Some(Value::String(s)) => s,
+        Some(val) => {
+            return Err(Error::new(ErrorKind::InvalidTypeInConfig,
+                                  &format!("expected string, got: {:?}", val)));
+        }
+    };
+
+    match style.as_str() {
+        "full" => Ok(PathStyle::Full),
+        "unique" => Ok(PathStyle::Unique),
+        "fish" => {
+            match c.get("modules.cwd.dir_length") {
+                None => Ok(PathStyle::Fish(1)),
+                Some(Value::Integer(n)) if n > 0 => Ok(PathStyle::Fish(n as usize)),
+                Some(val) => {
+                    Err(Error::new(ErrorKind::InvalidTypeInConfig,
+                                   &format!("expected positive integer, got: {:?}", val)))
+                }
+            }
+        }
+        _ => {
+            Err(Error::new(ErrorKind::NoSuchMatchInConfig,
+                           &format!("expected \"full\", \"fish\" or \"unique\", got: {:?}",
+                                    style)))
+        }
+    }
+}
+
+/// Shortens every directory in `cwd` but the last one, according to
+/// `style`. `real_cwd` is where `cwd` points to on disk, which is
+/// where the directories next to each one are looked up. The first
+/// `substituted` components are kept as they are.
+fn abbreviate(cwd: &Path, real_cwd: &Path, style: PathStyle, substituted: usize) -> PathBuf {
+    let count = cwd.components().count();
+    cwd.components()
+        .enumerate()
+        .map(|(i, component)| {
+            let name = match component {
+                Component::Normal(name) if i >= substituted && i + 1 < count => {
+                    name.to_string_lossy()
+                }
+                _ => return component.as_os_str().to_os_string(),
+            };
+            let short = match style {
+                PathStyle::Full => name.to_string(),
+                PathStyle::Fish(length) => fish_prefix(&name, length),
+                // `cwd` and `real_cwd` only differ in how they start,
+                // so counting from the end works for both
+                PathStyle::Unique => unique_prefix(&name, real_cwd.ancestors().nth(count - i)),
+            };
+            OsString::from(short)
+        })
+        .collect()
+}
+
+/// Cuts `name` down to its first `length` characters. The dot in front
+/// of hidden directories doesn't count, since it says little on its
+/// own.
+fn fish_prefix(name: &str, length: usize) -> String {
+    let dot = if name.starts_with('.') { 1 } else { 0 };
+    name.chars().take(dot + length).collect()
+}
+
+/// Cuts `name` down to the shortest prefix that no other directory in
+/// `parent` starts with. If `parent` can't be read, `name` is kept
+/// whole.
+fn unique_prefix(name: &str, parent: Option<&Path>) -> String {
+    let siblings: Vec<String> = match parent.and_then(|parent| fs::read_dir(parent).ok()) {
+        Some(entries) => {
+            entries
+                .filter_map(Result::ok)
+                .filter(|entry| entry.path().is_dir())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|sibling| sibling != name)
+                .collect()
+        }
+        None => return name.to_string(),
+    };
+
+    name.char_indices()
+        .map(|(i, c)| &name[..i + c.len_utf8()])
+        .find(|prefix| !siblings.iter().any(|sibling| sibling.starts_with(prefix)))
+        .unwrap_or(name)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::env;
+
+    use ansi_term::Color;
+    use git2::Repository;
+    use tempdir::TempDir;
+
+    use utils::load_config;
+
+    fn render(c: &Config, pwd: &Path, home: &Path) -> Result<FormatResult, Error> {
+        let mut env = Environment::default();
+        env.vars
+            .insert(String::from("PWD"), pwd.to_string_lossy().into_owned());
+        env.vars
+            .insert(String::from("HOME"), home.to_string_lossy().into_owned());
+        // The paths being shown don't have to exist, but the actual
+        // working directory does. Otherwise it's reported as missing.
+        env.current_dir = Some(env::temp_dir());
+        let ctx = Context::for_test(c, &env);
+
+        Cwd.render(&ctx)
+    }
+
+    #[test]
+    fn test_format_cwd_fish() {
+        let mut c = Config::new();
+        let pwd = Path::new("/home/user/projects/.config/contrail/src");
+        let home = Path::new("/home/user");
+
+        let result = render(&c, pwd, home).unwrap();
+        assert_eq!(result.output,
+                   Some(vec![Segment::new(".../projects/.config/contrail/src")]));
+
+        // Abbreviated paths aren't cut off at any depth by default
+        c.set("modules.cwd.path_style", "fish").unwrap();
+        let result = render(&c, pwd, home).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("~/p/.c/c/src")]));
+
+        c.set("modules.cwd.dir_length", 3).unwrap();
+        let result = render(&c, pwd, home).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("~/pro/.con/con/src")]));
+
+        c.set("modules.cwd.max_depth", 2).unwrap();
+        let result = render(&c, pwd, home).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new(".../con/src")]));
+
+        c.set("modules.cwd.dir_length", 0).unwrap();
+        assert!(render(&c, pwd, home).is_err());
+
+        c.set("modules.cwd.path_style", "short").unwrap();
+        assert!(render(&c, pwd, home).is_err());
+    }
+
+    #[test]
+    fn test_format_cwd_unique() {
+        let tmp = TempDir::new("contrail").unwrap();
+        for dir in &["projects/contrail/src", "projects/config", "projects/cargo", "pictures"] {
+            fs::create_dir_all(tmp.path().join(dir)).unwrap();
+        }
+        // Files can't be `cd`'d into, so they don't make a directory
+        // any more ambiguous
+        fs::File::create(tmp.path().join("projects/contrail.toml")).unwrap();
+
+        let mut c = Config::new();
+        c.set("modules.cwd.path_style", "unique").unwrap();
+        let result = render(&c, &tmp.path().join("projects/contrail/src"), tmp.path()).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("~/pr/cont/src")]));
+    }
+
+    #[test]
+    fn test_format_cwd_repo_relative() {
+        let tmp = TempDir::new("contrail").unwrap();
+        let root = tmp.path().join("contrail");
+        Repository::init(&root).unwrap();
+        fs::create_dir_all(root.join("src/modules")).unwrap();
+
+        let mut c = Config::new();
+        c.set("modules.cwd.repo_relative", true).unwrap();
+        c.set("modules.cwd.path_style", "fish").unwrap();
+        c.set("modules.cwd.style_repo.foreground", "red").unwrap();
+        let style_repo = ModuleStyle {
+            foreground: Some(Color::Red),
+            text_properties: Some(Style::new().bold()),
+            ..ModuleStyle::default()
+        };
+
+        let render_in = |dir: &Path| {
+            let env = Environment {
+                current_dir: Some(dir.to_path_buf()),
+                ..Environment::default()
+            };
+            let ctx = Context::for_test(&c, &env);
+            Cwd.render(&ctx).unwrap().output
+        };
+
+        assert_eq!(render_in(&root.join("src/modules")),
+                   Some(vec![Segment::styled("contrail", style_repo), Segment::new("/s/modules")]));
+        assert_eq!(render_in(&root),
+                   Some(vec![Segment::styled("contrail", style_repo)]));
+
+        // Outside of a repository, the whole path is shown
+        let outside = render_in(tmp.path()).unwrap();
+        assert_eq!(outside.len(), 1);
+        assert_eq!(outside[0].style, ModuleStyle::default());
+    }
+
+    #[test]
+    fn test_format_cwd_split() {
+        let mut c = Config::new();
+        c.set("modules.cwd.path_separator", " > ").unwrap();
+        let bold = ModuleStyle {
+            text_properties: Some(Style::new().bold()),
+            ..ModuleStyle::default()
+        };
+
+        let result = render(&c, Path::new("/home/user/src"), Path::new("/home/user")).unwrap();
+        assert_eq!(result.output,
+                   Some(vec![Segment::new("~"),
+                             Segment::new(" > "),
+                             Segment::styled("src", bold)]));
+
+        c.set("modules.cwd.style_current.foreground", "red").unwrap();
+        let result = render(&c, Path::new("/usr/bin"), Path::new("/home/user")).unwrap();
+        assert_eq!(result.output,
+                   Some(vec![Segment::new("/"),
+                             Segment::new(" > "),
+                             Segment::new("usr"),
+                             Segment::new(" > "),
+                             Segment::styled("bin",
+                                             ModuleStyle {
+                                                 foreground: Some(Color::Red),
+                                                 ..bold
+                                             })]));
+    }
+
+    #[test]
+    fn test_format_cwd_substitutions() {
+        let tmp = TempDir::new("contrail").unwrap();
+        let config_path = tmp.path().join("contrail.toml");
+        fs::write(&config_path,
+                  r#"
+[modules.cwd.substitutions]
+"/home/user/work" = "work"
+"/home/user/work/platform" = "🛠 platform"
+"/mnt/nfs/shared" = "nfs"
+"#)
+                .unwrap();
+        let c = load_config(config_path.to_str().unwrap()).unwrap();
+        let home = Path::new("/home/user");
+
+        // The longest match wins, whatever order they're listed in
+        let result = render(&c, Path::new("/home/user/work/platform/api"), home).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("🛠 platform/api")]));
+        let result = render(&c, Path::new("/home/user/work/website"), home).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("work/website")]));
+        let result = render(&c, Path::new("/home/user/music"), home).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("~/music")]));
+
+        // Only whole directories are matched
+        let result = render(&c, Path::new("/mnt/nfs/shared2"), home).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("/mnt/nfs/shared2")]));
+
+        // Substitutions happen before the path is cut off
+        let result = render(&c, Path::new("/mnt/nfs/shared/a/b/c/d"), home).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new(".../a/b/c/d")]));
+        let result = render(&c, Path::new("/mnt/nfs/shared/a/b/c"), home).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("nfs/a/b/c")]));
+
+        // They can be listed in an array of tables as well
+        fs::write(&config_path,
+                  r#"
+[[modules.cwd.substitutions]]
+from = "/home/user/work"
+to = "work"
+
+[[modules.cwd.substitutions]]
+from = "/home/user/work/platform"
+to = "🛠 platform"
+"#)
+                .unwrap();
+        let c = load_config(config_path.to_str().unwrap()).unwrap();
+        let result = render(&c, Path::new("/home/user/work/platform/api"), home).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("🛠 platform/api")]));
+
+        // But not given as anything else
+        let mut c = Config::new();
+        c.set("modules.cwd.substitutions", vec!["/mnt/nfs/shared"])
+            .unwrap();
+        assert!(render(&c, Path::new("/"), home).is_err());
+        let mut c = Config::new();
+        c.set("modules.cwd.substitutions", "/mnt/nfs/shared")
+            .unwrap();
+        assert!(render(&c, Path::new("/"), home).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_format_cwd_substitutions_symlink() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = TempDir::new("contrail").unwrap();
+        let platform = tmp.path().join("work/platform");
+        fs::create_dir_all(platform.join("api")).unwrap();
+        symlink(&platform, tmp.path().join("link")).unwrap();
+
+        let mut table = HashMap::new();
+        table.insert(String::from("from"), platform.to_string_lossy().into_owned());
+        table.insert(String::from("to"), String::from("platform"));
+        let mut c = Config::new();
+        c.set("modules.cwd.substitutions", vec![table]).unwrap();
+        c.set("modules.cwd.path_style", "fish").unwrap();
+
+        // $PWD goes through the symlink, but the substitution still
+        // applies to where it leads
+        let mut env = Environment::default();
+        env.vars
+            .insert(String::from("PWD"),
+                    tmp.path().join("link/api").to_string_lossy().into_owned());
+        env.current_dir = Some(platform.join("api"));
+        let ctx = Context::for_test(&c, &env);
+        let result = Cwd.render(&ctx).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("platform/api")]));
+
+        // The other way around, a substitution given through a symlink
+        // applies to the directory it leads to
+        let mut table = HashMap::new();
+        table.insert(String::from("from"),
+                     tmp.path().join("link").to_string_lossy().into_owned());
+        table.insert(String::from("to"), String::from("link"));
+        c.set("modules.cwd.substitutions", vec![table]).unwrap();
+        env.vars
+            .insert(String::from("PWD"),
+                    platform.join("api").to_string_lossy().into_owned());
+        let ctx = Context::for_test(&c, &env);
+        let result = Cwd.render(&ctx).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("link/api")]));
+    }
+
+    #[test]
+    fn test_format_cwd_missing() {
+        let mut c = Config::new();
+        let mut env = Environment::default();
+        env.vars
+            .insert(String::from("PWD"), String::from("/tmp/build"));
+
+        // The working directory is gone if it can't be found, or if it
+        // was deleted after the fact
+        for current_dir in [None, Some(PathBuf::from("/nonexistent/build"))] {
+            env.current_dir = current_dir;
+            let ctx = Context::for_test(&c, &env);
+            let result = Cwd.render(&ctx).unwrap();
+            assert_eq!(result.output,
+                       Some(vec![Segment::new("/tmp/build"), Segment::new(" ⚠")]));
+        }
+
+        c.set("modules.cwd.symbol_missing", "?").unwrap();
+        c.set("modules.cwd.style.background", "blue").unwrap();
+        c.set("modules.cwd.style_missing.background", "red")
+            .unwrap();
+        let ctx = Context::for_test(&c, &env);
+        let result = Cwd.render(&ctx).unwrap();
+        assert_eq!(result.output,
+                   Some(vec![Segment::new("/tmp/build"), Segment::new("?")]));
+        assert_eq!(result.options.style.background, Some(Color::Red));
+    }
+
+    #[test]
+    fn test_dir_state() {
+        let tmp = TempDir::new("contrail").unwrap();
+
+        assert_eq!(dir_state(Some(tmp.path()), |_| true), None);
+        assert_eq!(dir_state(Some(tmp.path()), |_| false),
+                   Some(("readonly", " 🔒")));
+        // A missing directory can't be written to either, but that's
+        // not what's wrong with it
+        assert_eq!(dir_state(Some(&tmp.path().join("gone")), |_| false),
+                   Some(("missing", " ⚠")));
+        assert_eq!(dir_state(None, |_| true), Some(("missing", " ⚠")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_format_cwd_readonly() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Root can write anywhere, so there's nothing to test
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+
+        let tmp = TempDir::new("contrail").unwrap();
+        let dir = tmp.path().join("build");
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+
+        let mut c = Config::new();
+        c.set("modules.cwd.style_readonly.foreground", "red")
+            .unwrap();
+        let env = Environment {
+            current_dir: Some(dir.clone()),
+            ..Environment::default()
+        };
+        let ctx = Context::for_test(&c, &env);
+        let result = Cwd.render(&ctx).unwrap();
+        assert_eq!(result.output.unwrap().pop(), Some(Segment::new(" 🔒")));
+        assert_eq!(result.options.style.foreground, Some(Color::Red));
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        let ctx = Context::for_test(&c, &env);
+        let result = Cwd.render(&ctx).unwrap();
+        assert_eq!(result.output.unwrap().len(), 1);
+        assert_eq!(result.options.style, ModuleStyle::default());
+    }
+
+    #[test]
+    fn test_format_cwd_max_length() {
+        let mut c = Config::new();
+        let home = Path::new("/home/user");
+        c.set("modules.cwd.max_length", 12).unwrap();
+        c.set("modules.cwd.ellipsis", "…").unwrap();
+
+        let pwd = Path::new("/home/user/projects/contrail/src/modules");
+        let result = render(&c, pwd, home).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("…/modules")]));
+
+        c.set("modules.cwd.max_length", 13).unwrap();
+        let result = render(&c, pwd, home).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("…/src/modules")]));
+
+        // Wide characters take up two columns each, and the current
+        // directory is kept even if it doesn't fit
+        let pwd = Path::new("/home/user/日本語/ドキュメント");
+        c.set("modules.cwd.max_length", 20).unwrap();
+        let result = render(&c, pwd, home).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("…/ドキュメント")]));
+        c.set("modules.cwd.max_length", 5).unwrap();
+        let result = render(&c, pwd, home).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("…/ドキュメント")]));
+
+        // A lone "~" isn't replaced by a wider ellipsis
+        c.set("modules.cwd.ellipsis", "...").unwrap();
+        let result = render(&c, Path::new("/home/user/contrail"), home).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("~/contrail")]));
+
+        // Splitting the path up doesn't change where it's cut
+        c.set("modules.cwd.max_length", 17).unwrap();
+        c.set("modules.cwd.path_separator", " > ").unwrap();
+        let bold = ModuleStyle {
+            text_properties: Some(Style::new().bold()),
+            ..ModuleStyle::default()
+        };
+        let result = render(&c, Path::new("/usr/local/share/man"), home).unwrap();
+        assert_eq!(result.output,
+                   Some(vec![Segment::new("..."),
+                             Segment::new(" > "),
+                             Segment::new("share"),
+                             Segment::new(" > "),
+                             Segment::styled("man", bold)]));
+
+        c.set("modules.cwd.max_length", -1).unwrap();
+        assert!(render(&c, pwd, home).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use config::Value;
+
+use utils::{Error, ErrorKind, FormatResult};
+
+use modules::{self, Context, Module, Segment};
+
+/// Shows how long the last command took to run, if it took long
+/// enough to be worth mentioning.
+pub struct Duration;
+
+impl Module for Duration {
+    fn name(&self) -> &str {
+        "duration"
+    }
+
+    /// Formats how long the last command took to run using whatever
+    /// options are present in the config file provided. Nothing is
+    /// shown if the command finished faster than
+    /// `modules.duration.min_duration` milliseconds.
+    ///
+    /// Returns an `Error` if it encounters any errors while parsing the
+    /// config file.
+    fn render(&self, ctx: &Context) -> Result<FormatResult, Error> {
+        let c = ctx.config;
+        let options = modules::read_options("duration", c)?;
+
+        let min_duration: u64 = if let Some(val) = c.get("modules.duration.min_duration") {
+            match val {
+                Value::Integer(n) if n >= 0 => n as u64,
+                _ => {
+                    return Err(Error::new(ErrorKind::InvalidTypeInConfig,
+                                          &format!("expected u64, got: {:?}", val)));
+                }
+            }
+        } else {
+            // Default minimum duration is 2 seconds
+            2000
+        };
+
+        if ctx.duration < min_duration {
+            return Ok(FormatResult::default());
+        }
+
+        let format_result = FormatResult {
+            output: Some(vec![Segment::new(humanize(ctx.duration))]),
+            options: options,
+        };
+
+        Ok(format_result)
+    }
+}
+
+/// Turns a duration in milliseconds into something readable, like
+/// "1h 2m 3s". Durations shorter than a second are shown in
+/// milliseconds.
+fn humanize(ms: u64) -> String {
+    if ms < 1000 {
+        return format!("{}ms", ms);
+    }
+
+    let secs = ms / 1000;
+    let (hours, mins, secs) = (secs / 3600, secs % 3600 / 60, secs % 60);
+
+    if hours > 0 {
+        format!("{}h {}m {}s", hours, mins, secs)
+    } else if mins > 0 {
+        format!("{}m {}s", mins, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use config::Config;
+
+    use modules::Environment;
+
+    fn render(c: &Config, duration: u64) -> Result<FormatResult, Error> {
+        let env = Environment::default();
+        let ctx = Context {
+            duration: duration,
+            ..Context::for_test(c, &env)
+        };
+
+        Duration.render(&ctx)
+    }
+
+    #[test]
+    fn test_format_duration() {
+        let mut c = Config::new();
+
+        // Quick commands aren't shown
+        let result = render(&c, 1999).unwrap();
+        assert_eq!(result.output, None);
+
+        let result = render(&c, 2000).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("2s")]));
+
+        // The threshold is configurable
+        c.set("modules.duration.min_duration", 0).unwrap();
+        let result = render(&c, 0).unwrap();
+        assert_eq!(result.output, Some(vec![Segment::new("0ms")]));
+
+        c.set("modules.duration.min_duration", -1).unwrap();
+        assert!(render(&c, 0).is_err());
+    }
+
+    #[test]
+    fn test_humanize() {
+        assert_eq!(humanize(999), "999ms");
+        assert_eq!(humanize(5300), "5s");
+        assert_eq!(humanize(65000), "1m 5s");
+        assert_eq!(humanize(3723000), "1h 2m 3s");
+    }
+}
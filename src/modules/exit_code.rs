@@ -1,60 +1,72 @@
-use ansi_term::Color;
-use config::Config;
-use clap::Shell;
-
 use utils::{Error, FormatResult};
 
-use modules;
-
-/// Formats the exit code module using whatever options are present in
-/// the config file provided.
-///
-/// Returns an `Error` if it encounters any errors while parsing the
-/// config file.
-pub fn format_exit_code(c: &Config,
-                        exit_code: u8,
-                        next_bg: Option<Color>,
-                        shell: Shell)
-                        -> Result<FormatResult, Error> {
-    let mut options = modules::read_options("exit_code", c)?;
-
-    let style_success = modules::read_style("modules.exit_code.style_success", c)?;
-    let style_error = modules::read_style("modules.exit_code.style_error", c)?;
-
-    // A command exited successfully if and only if the exit code is 0
-    if exit_code == 0 {
-        options.style = style_success;
-    } else {
-        options.style = style_error;
+use modules::{self, Context, Module, Segment};
+
+/// Shows the exit code of the last command, styled differently
+/// depending on whether or not it succeeded.
+pub struct ExitCode;
+
+impl Module for ExitCode {
+    fn name(&self) -> &str {
+        "exit_code"
     }
 
-    let format_result = FormatResult {
-        output: Some(modules::format_for_module(exit_code.to_string(), &options, next_bg, shell)),
-        next_bg: options.style.background,
-    };
+    /// Formats the exit code module using whatever options are present
+    /// in the config file provided.
+    ///
+    /// Returns an `Error` if it encounters any errors while parsing the
+    /// config file.
+    fn render(&self, ctx: &Context) -> Result<FormatResult, Error> {
+        let c = ctx.config;
+        let mut options = modules::read_options("exit_code", c)?;
+
+        let style_success = modules::read_style("modules.exit_code.style_success", c)?;
+        let style_error = modules::read_style("modules.exit_code.style_error", c)?;
+
+        // A command exited successfully if and only if the exit code is 0
+        if ctx.exit_code == 0 {
+            options.style = style_success;
+        } else {
+            options.style = style_error;
+        }
 
-    Ok(format_result)
+        let format_result = FormatResult {
+            output: Some(vec![Segment::new(ctx.exit_code.to_string())]),
+            options: options,
+        };
+
+        Ok(format_result)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use ansi_term::Color;
+    use config::Config;
+
+    use modules::Environment;
+
     #[test]
     fn test_exit_code() {
         let mut c = Config::new();
+        let env = Environment::default();
 
         c.set("modules.exit_code.style_success.background", "green")
             .unwrap();
         c.set("modules.exit_code.style_error.background", "red")
             .unwrap();
 
+        let mut ctx = Context::for_test(&c, &env);
+
         // Exit code of 0 should be green
-        let result = format_exit_code(&c, 0, None, Shell::Bash).unwrap();
-        assert_eq!(result.next_bg, Some(Color::Green));
+        let result = ExitCode.render(&ctx).unwrap();
+        assert_eq!(result.options.style.background, Some(Color::Green));
 
         // Exit code of non-zero should be red
-        let result = format_exit_code(&c, 1, None, Shell::Bash).unwrap();
-        assert_eq!(result.next_bg, Some(Color::Red));
+        ctx.exit_code = 1;
+        let result = ExitCode.render(&ctx).unwrap();
+        assert_eq!(result.options.style.background, Some(Color::Red));
     }
 }
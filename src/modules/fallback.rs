@@ -0,0 +1,63 @@
+use ansi_term::Color;
+
+use utils::FormatResult;
+
+use modules::{self, Context, Segment};
+
+/// Formats the segment shown in place of a module that failed to
+/// render.
+///
+/// Its options are read from `[modules.error]`, but since this must
+/// never fail, any options that can't be parsed are ignored. Unless a
+/// color is configured, it shows up as a white "!" on a red
+/// background.
+pub fn format_fallback(ctx: &Context) -> FormatResult {
+    let mut options = modules::read_options("error", ctx.config).unwrap_or_default();
+
+    if options.style.background.is_none() && options.style.foreground.is_none() {
+        options.style.background = Some(Color::Red);
+        options.style.foreground = Some(Color::White);
+    }
+
+    FormatResult {
+        output: Some(vec![Segment::new("!")]),
+        options: options,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use config::Config;
+
+    use modules::Environment;
+
+    #[test]
+    fn test_format_fallback() {
+        let mut c = Config::new();
+        let env = Environment::default();
+
+        // Red by default
+        {
+            let ctx = Context::for_test(&c, &env);
+            let result = format_fallback(&ctx);
+            assert_eq!(result.options.style.background, Some(Color::Red));
+            assert_eq!(result.output, Some(vec![Segment::new("!")]));
+        }
+
+        // Configurable, but a broken config can't stop it from rendering
+        c.set("modules.error.style.background", "yellow").unwrap();
+        c.set("modules.error.output", "oops").unwrap();
+        {
+            let ctx = Context::for_test(&c, &env);
+            let result = format_fallback(&ctx);
+            assert_eq!(result.options.style.background, Some(Color::Yellow));
+            assert_eq!(result.options.output, Some(String::from("oops")));
+        }
+
+        c.set("modules.error.style.background", "grey").unwrap();
+        let ctx = Context::for_test(&c, &env);
+        assert_eq!(format_fallback(&ctx).options.style.background, Some(Color::Red));
+    }
+}
@@ -1,30 +1,42 @@
-use ansi_term::Color;
-use config::Config;
-use clap::Shell;
-
 use utils::{Error, FormatResult};
 
-use modules;
-
-/// Formats a user-defined module using whatever options are present
-/// in the config file provided.
-///
-/// Returns an `Error` if it encounters any errors while parsing the
-/// config file.
-pub fn format_generic(name: &str,
-                      c: &Config,
-                      next_bg: Option<Color>,
-                      shell: Shell)
-                      -> Result<FormatResult, Error> {
-    let options = modules::read_options(name, c)?;
-
-    if options.output.is_some() {
-        Ok(FormatResult {
-               output: Some(modules::format_for_module("", &options, next_bg, shell)),
-               next_bg: options.style.background,
-           })
-    } else {
-        Ok(FormatResult::default())
+use modules::{self, Context, Module};
+
+/// A user-defined module, which displays whatever output is set for it
+/// in the config file.
+pub struct Generic {
+    name: String,
+}
+
+impl Generic {
+    pub fn new(name: &str) -> Generic {
+        Generic { name: name.to_string() }
+    }
+}
+
+impl Module for Generic {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Formats a user-defined module using whatever options are present
+    /// in the config file provided.
+    ///
+    /// Returns an `Error` if it encounters any errors while parsing the
+    /// config file.
+    fn render(&self, ctx: &Context) -> Result<FormatResult, Error> {
+        let options = modules::read_options(&self.name, ctx.config)?;
+
+        if options.output.is_some() {
+            // The content is replaced by the output when the module is
+            // drawn, so there's nothing to fill in here
+            Ok(FormatResult {
+                   output: Some(Vec::new()),
+                   options: options,
+               })
+        } else {
+            Ok(FormatResult::default())
+        }
     }
 }
 
@@ -32,23 +44,30 @@ pub fn format_generic(name: &str,
 mod tests {
     use super::*;
 
+    use config::Config;
+
+    use modules::Environment;
+
     #[test]
     fn test_format_generic() {
-        use ansi_term::ANSIString;
-
         let mut c = Config::new();
+        let env = Environment::default();
 
         // Not in the config file == nothing to print
-        assert_eq!(format_generic("nonexistant", &c, None, Shell::Bash)
-                       .unwrap()
-                       .output,
-                   None);
+        {
+            let ctx = Context::for_test(&c, &env);
+            assert_eq!(Generic::new("nonexistant")
+                           .render(&ctx)
+                           .unwrap()
+                           .output,
+                       None);
+        }
 
         c.set("modules.exists.output", "hello").unwrap();
 
-        assert_eq!(format_generic("exists", &c, None, Shell::Bash)
-                       .unwrap()
-                       .output,
-                   Some(ANSIString::from(" hello ")));
+        let ctx = Context::for_test(&c, &env);
+        let result = Generic::new("exists").render(&ctx).unwrap();
+        assert!(result.output.is_some());
+        assert_eq!(result.options.output, Some(String::from("hello")));
     }
 }
@@ -1,13 +1,332 @@
+use std::collections::HashMap;
 use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use ansi_term::Color;
-use config::Config;
-use clap::Shell;
-use git2::{Branch, Repository};
+use config::{Config, Value};
+use git2::{self, Branch, DescribeOptions, Oid, Repository, RepositoryOpenFlags,
+           RepositoryState, Status, StatusOptions};
 
-use utils::{Error, FormatResult};
+use utils::{Error, ErrorKind, FormatResult};
 
-use modules;
+use modules::{self, Context, Environment, Module, ModuleStyle, Segment, Template, Variable};
+
+/// Shows the branch and state of the current git repository, if any.
+pub struct Git;
+
+impl Module for Git {
+    fn name(&self) -> &str {
+        "git"
+    }
+
+    fn render(&self, ctx: &Context) -> Result<FormatResult, Error> {
+        format_git(ctx)
+    }
+}
+
+/// Kinds of changes to the files of a repository that can be counted
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StatusKind {
+    Conflicted,
+    Staged,
+    Modified,
+    Renamed,
+    Deleted,
+    Untracked,
+}
+
+/// Every `StatusKind`, in the order they're displayed in
+const STATUS_KINDS: [StatusKind; 6] = [StatusKind::Conflicted,
+                                       StatusKind::Staged,
+                                       StatusKind::Modified,
+                                       StatusKind::Renamed,
+                                       StatusKind::Deleted,
+                                       StatusKind::Untracked];
+
+impl StatusKind {
+    /// Name used for the options of this kind in the config file,
+    /// like `modules.git.show_<name>`
+    fn name(self) -> &'static str {
+        match self {
+            StatusKind::Conflicted => "conflicted",
+            StatusKind::Staged => "staged",
+            StatusKind::Modified => "modified",
+            StatusKind::Renamed => "renamed",
+            StatusKind::Deleted => "deleted",
+            StatusKind::Untracked => "untracked",
+        }
+    }
+
+    fn default_symbol(self) -> &'static str {
+        match self {
+            StatusKind::Conflicted => "=",
+            StatusKind::Staged => "●",
+            StatusKind::Modified => "!",
+            StatusKind::Renamed => "»",
+            StatusKind::Deleted => "✘",
+            StatusKind::Untracked => "?",
+        }
+    }
+
+    /// Whether a file with the given `Status` counts towards this kind.
+    /// Conflicted files only ever count as conflicted.
+    fn matches(self, status: Status) -> bool {
+        if status.contains(git2::STATUS_CONFLICTED) {
+            return self == StatusKind::Conflicted;
+        }
+
+        match self {
+            StatusKind::Conflicted => false,
+            StatusKind::Staged => {
+                status.intersects(git2::STATUS_INDEX_NEW | git2::STATUS_INDEX_MODIFIED |
+                                  git2::STATUS_INDEX_DELETED |
+                                  git2::STATUS_INDEX_RENAMED |
+                                  git2::STATUS_INDEX_TYPECHANGE)
+            }
+            StatusKind::Modified => {
+                status.intersects(git2::STATUS_WT_MODIFIED | git2::STATUS_WT_TYPECHANGE)
+            }
+            StatusKind::Renamed => {
+                status.intersects(git2::STATUS_INDEX_RENAMED | git2::STATUS_WT_RENAMED)
+            }
+            StatusKind::Deleted => {
+                status.intersects(git2::STATUS_INDEX_DELETED | git2::STATUS_WT_DELETED)
+            }
+            StatusKind::Untracked => status.contains(git2::STATUS_WT_NEW),
+        }
+    }
+}
+
+/// How to show the number of files with a certain kind of change
+#[derive(Debug, PartialEq)]
+struct Counter {
+    kind: StatusKind,
+    symbol: String,
+    style: ModuleStyle,
+}
+
+/// Names of the variables that can be used in `modules.git.format`,
+/// apart from the name of each `StatusKind`
+const VARIABLES: [&str; 7] = ["branch", "state", "dirty", "stash", "ahead", "behind", "timeout"];
+
+/// Options specific to the git module
+#[derive(Debug, PartialEq)]
+struct GitOptions {
+    /// Layout of the module
+    format: Template,
+    /// Style of the branch name
+    style_branch: ModuleStyle,
+    /// Style of the marker shown when tracked files have been modified
+    style_dirty: ModuleStyle,
+    /// Style of the number of commits ahead of upstream
+    style_ahead: ModuleStyle,
+    /// Style of the number of commits behind upstream
+    style_behind: ModuleStyle,
+    /// Shown in front of the commit id when HEAD is detached
+    symbol_detached: String,
+    /// Shown in front of the tag name when HEAD is detached at a tag
+    symbol_tag: String,
+    /// Style of a detached HEAD, on top of the style of the branch
+    style_detached: ModuleStyle,
+    /// How to show each kind of change, in the same order as
+    /// `STATUS_KINDS`
+    counters: Vec<Counter>,
+    /// Shown in front of the number of stashes
+    symbol_stash: String,
+    /// Style of the number of stashes
+    style_stash: ModuleStyle,
+    /// Style of the operation in progress
+    style_state: ModuleStyle,
+    /// How long the slow parts of the module may take, if there's a
+    /// limit at all
+    timeout: Option<Duration>,
+    /// Shown in place of the slow parts if they took too long
+    symbol_timeout: String,
+    /// Style of `symbol_timeout`
+    style_timeout: ModuleStyle,
+    /// Whether to search for untracked files at all
+    scan_untracked: bool,
+    /// How long the daemon may reuse the slow parts of the module, if
+    /// there's a limit besides the repository changing
+    cache_ttl: Option<Duration>,
+}
+
+impl GitOptions {
+    /// Reads the options of the git module from a config file.
+    ///
+    /// The layout comes from `modules.git.format`. Without it, the
+    /// branch is followed by whichever parts are turned on with their
+    /// `show_<part>` toggle (only the operation in progress and the
+    /// dirty marker are on by default). Each kind of change, as well
+    /// as the number of stashes, has its own `symbol_<kind>` and
+    /// `style_<kind>` under `modules.git`. The branch, dirty marker
+    /// and commits ahead and behind are styled through `style_branch`,
+    /// `style_dirty`, `style_ahead` and `style_behind`.
+    ///
+    /// Returns an `Error` if any of the options fail to be parsed, or
+    /// if the format refers to a variable that doesn't exist.
+    fn read(c: &Config) -> Result<GitOptions, Error> {
+        let mut counters = Vec::new();
+        for &kind in &STATUS_KINDS {
+            let key = |option: &str| format!("modules.git.{}_{}", option, kind.name());
+
+            counters.push(Counter {
+                              kind: kind,
+                              symbol: modules::read_string(&key("symbol"),
+                                                           kind.default_symbol(),
+                                                           c)?,
+                              style: modules::read_style(&key("style"), c)?,
+                          });
+        }
+
+        let format = if c.get("modules.git.format").is_some() {
+            Template::parse(&modules::read_string("modules.git.format", "", c)?)?
+        } else {
+            Template::parse(&default_format(c)?)?
+        };
+
+        for name in format.variables() {
+            if !VARIABLES.contains(&name) && !STATUS_KINDS.iter().any(|k| k.name() == name) {
+                return Err(Error::new(ErrorKind::NoSuchMatchInConfig,
+                                      &format!("no variable named {:?} in modules.git.format",
+                                               name)));
+            }
+        }
+
+        Ok(GitOptions {
+               format: format,
+               style_branch: modules::read_style("modules.git.style_branch", c)?,
+               style_dirty: modules::read_style("modules.git.style_dirty", c)?,
+               style_ahead: modules::read_style("modules.git.style_ahead", c)?,
+               style_behind: modules::read_style("modules.git.style_behind", c)?,
+               symbol_detached: modules::read_string("modules.git.symbol_detached", "@", c)?,
+               symbol_tag: modules::read_string("modules.git.symbol_tag", "#", c)?,
+               style_detached: modules::read_style("modules.git.style_detached", c)?,
+               counters: counters,
+               symbol_stash: modules::read_string("modules.git.symbol_stash", "$", c)?,
+               style_stash: modules::read_style("modules.git.style_stash", c)?,
+               style_state: modules::read_style("modules.git.style_state", c)?,
+               timeout: read_timeout(c)?,
+               symbol_timeout: modules::read_string("modules.git.symbol_timeout", "?", c)?,
+               style_timeout: modules::read_style("modules.git.style_timeout", c)?,
+               scan_untracked: modules::read_bool("modules.git.scan_untracked", true, c)?,
+               cache_ttl: read_cache_ttl(c)?,
+           })
+    }
+}
+
+/// Reads how many milliseconds the slow parts of the git module may
+/// take from `modules.git.timeout`. Zero, like leaving it out, means
+/// there's no limit.
+///
+/// Returns an `Error` if the timeout isn't a positive integer.
+fn read_timeout(c: &Config) -> Result<Option<Duration>, Error> {
+    match c.get("modules.git.timeout") {
+        None |
+        Some(Value::Integer(0)) => Ok(None),
+        Some(Value::Integer(n)) if n > 0 => Ok(Some(Duration::from_millis(n as u64))),
+        Some(val) => {
+            Err(Error::new(ErrorKind::InvalidTypeInConfig,
+                           &format!("expected u64, got: {:?}", val)))
+        }
+    }
+}
+
+/// Reads how many milliseconds the daemon may reuse the status of a
+/// repository for from `modules.git.cache_ttl`. Leaving it out means
+/// it's reused until the repository changes, and zero means it's never
+/// reused.
+///
+/// Returns an `Error` if it isn't a non-negative integer.
+fn read_cache_ttl(c: &Config) -> Result<Option<Duration>, Error> {
+    match c.get("modules.git.cache_ttl") {
+        None => Ok(None),
+        Some(Value::Integer(n)) if n >= 0 => Ok(Some(Duration::from_millis(n as u64))),
+        Some(val) => {
+            Err(Error::new(ErrorKind::InvalidTypeInConfig,
+                           &format!("expected u64, got: {:?}", val)))
+        }
+    }
+}
+
+/// Builds the format used when `modules.git.format` isn't set, out of
+/// the `show_<part>` toggles.
+///
+/// Returns an `Error` if any of the toggles aren't booleans.
+fn default_format(c: &Config) -> Result<String, Error> {
+    let mut format = String::from("{branch}");
+
+    if modules::read_bool("modules.git.show_state", true, c)? {
+        format.push_str("{state?}");
+    }
+    if modules::read_bool("modules.git.show_dirty", true, c)? {
+        format.push_str("{dirty?}");
+    }
+    format.push_str("{timeout?}");
+    for kind in &STATUS_KINDS {
+        if modules::read_bool(&format!("modules.git.show_{}", kind.name()), false, c)? {
+            format.push_str(&format!("{{{}?}}", kind.name()));
+        }
+    }
+    if modules::read_bool("modules.git.show_stash", false, c)? {
+        format.push_str("{stash?}");
+    }
+    format.push_str("{ahead?}{behind?}");
+
+    Ok(format)
+}
+
+/// Creates a variable for a count, which is left out of the format
+/// when it's zero.
+fn count_variable(count: usize, symbol: &str, style: ModuleStyle) -> Option<Variable> {
+    if count > 0 {
+        Some(Variable {
+                 value: count.to_string(),
+                 section: format!(" {}{}", symbol, count),
+                 style: style,
+             })
+    } else {
+        None
+    }
+}
+
+/// Summary of the status of every file in a repository
+#[derive(Clone, Debug, Default, PartialEq)]
+struct StatusSummary {
+    /// Number of files of each `StatusKind`, in the same order as
+    /// `STATUS_KINDS`
+    counts: [usize; 6],
+    /// Whether any tracked file in the working directory differs from
+    /// the index
+    dirty: bool,
+}
+
+impl StatusSummary {
+    fn count(&self, kind: StatusKind) -> usize {
+        self.counts[kind as usize]
+    }
+
+    /// Adds a file with the given `Status` to the summary.
+    fn add(&mut self, status: Status) {
+        if status.intersects(git2::STATUS_WT_MODIFIED | git2::STATUS_WT_DELETED |
+                             git2::STATUS_WT_TYPECHANGE |
+                             git2::STATUS_WT_RENAMED |
+                             git2::STATUS_CONFLICTED) {
+            self.dirty = true;
+        }
+
+        for &kind in &STATUS_KINDS {
+            if kind.matches(status) {
+                self.counts[kind as usize] += 1;
+            }
+        }
+    }
+}
 
 /// Finds and formats information about the current git repository, if
 /// any.
@@ -15,15 +334,16 @@ use modules;
 /// Returns an `Error` if there is an error while reading the config
 /// file. Errors encountered while fetching information about the
 /// current repository are simply ignored.
-pub fn format_git(c: &Config, next_bg: Option<Color>, shell: Shell) -> Result<FormatResult, Error> {
+fn format_git(ctx: &Context) -> Result<FormatResult, Error> {
     // This is one of the few modules that actually can return `None`
     // for its output. If that happens, no part of the module
     // (separator, padding, etc.) will show up in the prompt. (It will
     // be effectively "skipped")
 
-    let options = modules::read_options("git", c)?;
+    let options = modules::read_options("git", ctx.config)?;
+    let git_options = GitOptions::read(ctx.config)?;
 
-    let cwd = if let Ok(cwd) = env::current_dir() {
+    let cwd = if let Some(ref cwd) = ctx.env.current_dir {
         cwd
     } else {
         // Problem while getting the current directory, just skip this
@@ -31,73 +351,1415 @@ pub fn format_git(c: &Config, next_bg: Option<Color>, shell: Shell) -> Result<Fo
         return Ok(FormatResult::default());
     };
 
-    // Holds the final output
-    let mut output = String::new();
+    let output = if let Some(mut repo) = discover_repository(cwd, ctx.env) {
+        format_repository(&mut repo, &git_options, ctx.git_cache)
+    } else {
+        // Current working directory wasn't a git repository. Harmless
+        // error, return `None` so that we don't print anything, and
+        // just move on to the next module.
+        return Ok(FormatResult::default());
+    };
 
-    if let Ok(repo) = Repository::open(cwd) {
-        // Find and print the branch name ("master", etc...), but if
-        // the repository exists and the HEAD doesn't, just return
-        let local = if let Ok(h) = repo.head() {
-            h
-        } else {
-            return Ok(FormatResult::default());
-        };
+    if output.is_empty() {
+        Ok(FormatResult::default())
+    } else {
+        // If we get here, we *at least* have a branch name we can
+        // format.
+        Ok(FormatResult {
+               output: Some(output),
+               options: options,
+           })
+    }
+}
+
+/// Builds the contents of the git module for `repo`.
+///
+/// Returns no segments at all if there's nothing to show (for example, if
+/// the repository doesn't have a HEAD yet).
+fn format_repository(repo: &mut Repository,
+                     git_options: &GitOptions,
+                     cache: Option<&GitCache>)
+                     -> Vec<Segment> {
+    let start = Instant::now();
+    let format = &git_options.format;
+    let mut vars: HashMap<&str, Variable> = HashMap::new();
+
+    // Iterating over the stashes needs exclusive access to the
+    // repository, so count them before anything else borrows it
+    if format.uses("stash") {
+        if let Some(var) = count_variable(count_stashes(repo),
+                                          &git_options.symbol_stash,
+                                          git_options.style_stash) {
+            vars.insert("stash", var);
+        }
+    }
+
+    // Find the branch name ("master", etc...), but if the repository
+    // exists and the HEAD doesn't, just return
+    let local = if let Ok(h) = repo.head() {
+        h
+    } else {
+        return Vec::new();
+    };
+
+    // A detached HEAD has no branch name (its shorthand is just
+    // "HEAD"), so show the tag or commit it points to instead
+    let branch = if !local.is_branch() {
+        local
+            .target()
+            .map(|oid| {
+                     (describe_detached(repo, oid, git_options),
+                      git_options.style_detached.or(&git_options.style_branch))
+                 })
+    } else {
+        local
+            .shorthand()
+            .map(|name| (name.to_string(), git_options.style_branch))
+    };
+
+    if let Some((name, style)) = branch {
+        vars.insert("branch",
+                    Variable {
+                        value: name.clone(),
+                        section: name,
+                        style: style,
+                    });
+    }
 
-        if let Some(name) = local.shorthand() {
-            output.push_str(name);
+    // Show the operation that's in progress, so that it's obvious
+    // when the repository is in the middle of a rebase, merge, etc.
+    if format.uses("state") {
+        if let Some(state) = describe_state(repo) {
+            vars.insert("state",
+                        Variable {
+                            section: format!(" {}", state),
+                            value: state,
+                            style: git_options.style_state,
+                        });
         }
+    }
 
-        // Show whether or not the current working directory has been
-        // modified. If errors are encountered, just don't display
-        // anything for this part.
-        if let Ok(diff) = repo.diff_index_to_workdir(None, None) {
-            if let Ok(stats) = diff.stats() {
-                if stats.files_changed() > 0 {
-                    output.push_str(" +");
+    // Whether the working directory has been modified, how many
+    // files have changed in which way, and how far ahead/behind
+    // upstream the branch is can all take a while to find out in big
+    // repositories. If errors are encountered, just don't display
+    // anything for these parts.
+    let query = SlowQuery {
+        status: format.uses("dirty") || STATUS_KINDS.iter().any(|k| format.uses(k.name())),
+        untracked: if format.uses("untracked") {
+            untracked_files(repo, git_options.scan_untracked)
+        } else {
+            UntrackedFiles::No
+        },
+        ahead_behind: format.uses("ahead") || format.uses("behind"),
+    };
+
+    let remaining = git_options
+        .timeout
+        .map(|timeout| timeout.checked_sub(start.elapsed()).unwrap_or_default());
+    let slow_parts = if let Some(cache) = cache {
+        cache
+            .get(repo, query, git_options.cache_ttl)
+            .or_else(|| cache.scan(repo, query, remaining))
+    } else if let Some(remaining) = remaining {
+        compute_slow_parts_within(repo, query, remaining)
+    } else {
+        Some(compute_slow_parts(repo, query))
+    };
+
+    // The daemon can still show what its last scan found (next to the
+    // marker), as long as nothing has been staged or committed since
+    let timed_out = slow_parts.is_none();
+    let slow_parts = slow_parts.or_else(|| cache.and_then(|cache| cache.last(repo, query)));
+
+    if let Some(slow_parts) = slow_parts {
+        if let Some(summary) = slow_parts.summary {
+            if summary.dirty {
+                vars.insert("dirty",
+                            Variable {
+                                value: String::from("+"),
+                                section: String::from(" +"),
+                                style: git_options.style_dirty,
+                            });
+            }
+
+            for counter in &git_options.counters {
+                if let Some(var) = count_variable(summary.count(counter.kind),
+                                                  &counter.symbol,
+                                                  counter.style) {
+                    vars.insert(counter.kind.name(), var);
                 }
             }
         }
 
-        // Show whether whether or not the current working
-        // directory is ahead/behind upstream. If errors are
-        // encountered AT ANY POINT, don't display anything.
-        let local = Branch::wrap(local);
-        if let Ok(upstream) = local.upstream() {
-            let local_ref = local.get();
-            let upstream_ref = upstream.get();
-
-            if let Some(local_target) = local_ref.target() {
-                if let Some(upstream_target) = upstream_ref.target() {
-                    if let Ok((ahead, behind)) =
-                        repo.graph_ahead_behind(local_target, upstream_target) {
-                        // Show commits ahead
-                        if ahead > 0 {
-                            output.push_str(&format!(" ⇡{}", ahead));
-                        }
-
-                        // Show commits behind
-                        if behind > 0 {
-                            output.push_str(&format!(" ⇣{}", behind));
-                        }
-                    }
-                }
+        if let Some((ahead, behind)) = slow_parts.ahead_behind {
+            // Commits ahead
+            if let Some(var) = count_variable(ahead, "⇡", git_options.style_ahead) {
+                vars.insert("ahead", var);
+            }
+
+            // Commits behind
+            if let Some(var) = count_variable(behind, "⇣", git_options.style_behind) {
+                vars.insert("behind", var);
+            }
+        }
+    }
+
+    if timed_out {
+        // Ran out of time, so let the user know that the slow parts
+        // are missing (or stale) rather than pretending the
+        // repository is clean
+        vars.insert("timeout",
+                    Variable {
+                        value: git_options.symbol_timeout.clone(),
+                        section: format!(" {}", git_options.symbol_timeout),
+                        style: git_options.style_timeout,
+                    });
+    }
+
+    let mut segments = format.render(|name| vars.get(name).cloned());
+
+    // A format without a place for the marker still gets one, at the
+    // end
+    if !format.uses("timeout") {
+        if let Some(timeout) = vars.remove("timeout") {
+            segments.push(Segment::styled(timeout.section, timeout.style));
+        }
+    }
+    segments
+}
+
+/// Parts of the git module that can take a long time to compute in big
+/// repositories
+#[derive(Clone, Debug, Default, PartialEq)]
+struct SlowParts {
+    summary: Option<StatusSummary>,
+    ahead_behind: Option<(usize, usize)>,
+}
+
+/// Which of the `SlowParts` need to be computed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct SlowQuery {
+    status: bool,
+    untracked: UntrackedFiles,
+    ahead_behind: bool,
+}
+
+fn compute_slow_parts(repo: &Repository, query: SlowQuery) -> SlowParts {
+    SlowParts {
+        summary: if query.status {
+            summarize_statuses(repo, query.untracked)
+        } else {
+            None
+        },
+        ahead_behind: if query.ahead_behind {
+            ahead_behind(repo)
+        } else {
+            None
+        },
+    }
+}
+
+/// Computes the `SlowParts` of `repo` on another thread, giving up
+/// after `timeout`.
+///
+/// Returns `None` if it took too long, or if the repository couldn't
+/// be opened again.
+fn compute_slow_parts_within(repo: &Repository,
+                             query: SlowQuery,
+                             timeout: Duration)
+                             -> Option<SlowParts> {
+    if timeout == Duration::default() {
+        return None;
+    }
+
+    let git_dir = repo.path().to_path_buf();
+    let workdir = repo.workdir().map(Path::to_path_buf);
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || if let Some(repo) = reopen_repository(&git_dir, workdir) {
+                      let _ = sender.send(compute_slow_parts(&repo, query));
+                  });
+
+    // If the thread runs out of time it's simply left behind. Only a
+    // shell drawing its own prompt gets here, and it exits as soon as
+    // the prompt has been printed (the daemon uses `GitCache::scan`).
+    match receiver.recv_timeout(timeout) {
+        Ok(slow_parts) => Some(slow_parts),
+        Err(RecvTimeoutError::Timeout) => None,
+        // The thread couldn't open the repository, so there's nothing
+        // that could be shown (or remembered) either
+        Err(RecvTimeoutError::Disconnected) => None,
+    }
+}
+
+/// Opens the repository at `git_dir` with the given working directory.
+///
+/// `Repository` can't be shared between threads, so threads use this
+/// to open their own.
+fn reopen_repository(git_dir: &Path, workdir: Option<PathBuf>) -> Option<Repository> {
+    let repo = Repository::open(git_dir).ok()?;
+    if let Some(workdir) = workdir {
+        let _ = repo.set_workdir(&workdir, false);
+    }
+    Some(repo)
+}
+
+/// How many repositories the daemon remembers the `SlowParts` of
+const MAX_CACHE_ENTRIES: usize = 64;
+
+/// How many scans the daemon runs at once
+const MAX_SCANS: usize = 4;
+
+/// Remembers the `SlowParts` of every repository between prompts, for
+/// as long as the daemon keeps running.
+///
+/// An entry is thrown away as soon as the index, `HEAD`, the current
+/// branch or its upstream change, which happens whenever files are
+/// staged, commits are made, branches are switched, upstream is
+/// fetched, etc. Changes to files in the working directory don't touch
+/// any of them, so `cache_ttl` can put a limit on how long entries are
+/// reused for as well.
+///
+/// Scans that take longer than a prompt can wait keep running, and
+/// prompts drawn in the meantime wait for the same scan instead of
+/// starting another one.
+#[derive(Default)]
+pub struct GitCache {
+    entries: Arc<Mutex<HashMap<PathBuf, CacheEntry>>>,
+    scans: Arc<Mutex<Scans>>,
+}
+
+/// Everyone waiting for each running scan
+type Scans = HashMap<(PathBuf, SlowQuery), Vec<Sender<SlowParts>>>;
+
+struct CacheEntry {
+    query: SlowQuery,
+    stamp: RepositoryStamp,
+    /// When the scan started
+    created: Instant,
+    slow_parts: SlowParts,
+}
+
+/// Modification times of the files that change along with the status
+/// of a repository: the index, `HEAD`, `packed-refs`, and the refs of
+/// the current branch and its upstream
+#[derive(Debug, PartialEq)]
+struct RepositoryStamp {
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl RepositoryStamp {
+    fn read(repo: &Repository) -> RepositoryStamp {
+        // A linked worktree has its own index and HEAD, but shares the
+        // refs with the main repository
+        let common_dir = common_dir(repo.path());
+        let mut paths = vec![repo.path().join("index"),
+                             repo.path().join("HEAD"),
+                             common_dir.join("packed-refs")];
+
+        if let Ok(head) = repo.head() {
+            if head.is_branch() {
+                let branch = Branch::wrap(head);
+                let upstream = branch.upstream().ok();
+                let names = branch
+                    .get()
+                    .name()
+                    .into_iter()
+                    .chain(upstream.as_ref().and_then(|u| u.get().name()));
+                paths.extend(names.map(|name| common_dir.join(name)));
             }
         }
 
-        if output.is_empty() {
-            Ok(FormatResult::default())
+        RepositoryStamp {
+            modified: paths
+                .iter()
+                .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+                .collect(),
+        }
+    }
+}
+
+/// Finds the directory that holds the refs of the repository in
+/// `git_dir`. That's `git_dir` itself, unless it belongs to a linked
+/// worktree, whose `commondir` file points to the main repository's.
+fn common_dir(git_dir: &Path) -> PathBuf {
+    let contents = fs::read_to_string(git_dir.join("commondir")).unwrap_or_default();
+    git_dir.join(contents.trim_end())
+}
+
+impl GitCache {
+    /// Finds the `SlowParts` computed for `query` in `repo`, as long as
+    /// the repository hasn't changed since and they're younger than
+    /// `ttl` (if there's a limit).
+    fn get(&self,
+           repo: &Repository,
+           query: SlowQuery,
+           ttl: Option<Duration>)
+           -> Option<SlowParts> {
+        let age = self.entries.lock().ok()?.get(repo.path())?.created.elapsed();
+        match ttl {
+            Some(ttl) if age >= ttl => None,
+            _ => self.last(repo, query),
+        }
+    }
+
+    /// Finds the `SlowParts` computed for `query` in `repo`, no matter
+    /// how long ago, as long as the repository hasn't changed since.
+    fn last(&self, repo: &Repository, query: SlowQuery) -> Option<SlowParts> {
+        let entries = self.entries.lock().ok()?;
+        let entry = entries.get(repo.path())?;
+
+        if entry.query == query && entry.stamp == RepositoryStamp::read(repo) {
+            Some(entry.slow_parts.clone())
         } else {
-            // If we get here, we *at least* have a branch name we can
-            // format.
-            Ok(FormatResult {
-                   output: Some(modules::format_for_module(output, &options, next_bg, shell)),
-                   next_bg: options.style.background,
-               })
+            None
+        }
+    }
+
+    /// Computes the `SlowParts` of `repo` on another thread, or joins
+    /// the thread that's already computing them, and waits for at most
+    /// `timeout`. They're remembered once they're done, even if that's
+    /// too late for this prompt.
+    ///
+    /// Returns `None` if it took too long, if the repository couldn't
+    /// be opened again, or if too many other scans are running.
+    fn scan(&self,
+            repo: &Repository,
+            query: SlowQuery,
+            timeout: Option<Duration>)
+            -> Option<SlowParts> {
+        let key = (repo.path().to_path_buf(), query);
+        let (sender, receiver) = mpsc::channel();
+
+        {
+            let mut scans = self.scans.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(waiting) = scans.get_mut(&key) {
+                waiting.push(sender);
+            } else if scans.len() < MAX_SCANS {
+                scans.insert(key.clone(), vec![sender]);
+                self.start_scan(repo, key);
+            } else {
+                return None;
+            }
+        }
+
+        match timeout {
+            Some(timeout) => receiver.recv_timeout(timeout).ok(),
+            None => receiver.recv().ok(),
+        }
+    }
+
+    /// Starts the thread behind `scan`, which remembers its results
+    /// and hands them to everyone waiting for them.
+    fn start_scan(&self, repo: &Repository, key: (PathBuf, SlowQuery)) {
+        let workdir = repo.workdir().map(Path::to_path_buf);
+        let entries = Arc::clone(&self.entries);
+        let scans = Arc::clone(&self.scans);
+
+        thread::spawn(move || {
+            let (ref git_dir, query) = key;
+            let created = Instant::now();
+
+            let slow_parts = reopen_repository(git_dir, workdir).map(|repo| {
+                // Read before scanning, so that changes made during the
+                // scan aren't covered up
+                let stamp = RepositoryStamp::read(&repo);
+                let slow_parts = compute_slow_parts(&repo, query);
+
+                remember(&mut entries.lock().unwrap_or_else(|e| e.into_inner()),
+                         git_dir.clone(),
+                         CacheEntry {
+                             query: query,
+                             stamp: stamp,
+                             created: created,
+                             slow_parts: slow_parts.clone(),
+                         });
+                slow_parts
+            });
+
+            // If the repository couldn't be opened, dropping the senders
+            // lets everyone who's waiting know
+            let waiting = scans
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&key)
+                .unwrap_or_default();
+            if let Some(slow_parts) = slow_parts {
+                for sender in waiting {
+                    let _ = sender.send(slow_parts.clone());
+                }
+            }
+        });
+    }
+}
+
+/// Adds `entry` to `entries`, making room for it by throwing away the
+/// oldest entry if there are too many.
+fn remember(entries: &mut HashMap<PathBuf, CacheEntry>, git_dir: PathBuf, entry: CacheEntry) {
+    if entries.len() >= MAX_CACHE_ENTRIES && !entries.contains_key(&git_dir) {
+        let oldest = entries
+            .iter()
+            .min_by_key(|&(_, entry)| entry.created)
+            .map(|(git_dir, _)| git_dir.clone());
+        if let Some(oldest) = oldest {
+            entries.remove(&oldest);
+        }
+    }
+    entries.insert(git_dir, entry);
+}
+
+/// How thoroughly to search for untracked files, like git's
+/// `status.showUntrackedFiles`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum UntrackedFiles {
+    /// Don't search for untracked files at all
+    No,
+    /// Show untracked directories without looking inside them
+    Normal,
+    /// Show every untracked file, even inside untracked directories
+    All,
+}
+
+impl UntrackedFiles {
+    /// Name of the mode, as used by `git status --untracked-files`
+    fn name(self) -> &'static str {
+        match self {
+            UntrackedFiles::No => "no",
+            UntrackedFiles::Normal => "normal",
+            UntrackedFiles::All => "all",
+        }
+    }
+}
+
+/// Decides how to search for untracked files in `repo`. They're never
+/// searched for unless `scan` is set, and otherwise the repository's
+/// own `status.showUntrackedFiles` is honored.
+fn untracked_files(repo: &Repository, scan: bool) -> UntrackedFiles {
+    if !scan {
+        return UntrackedFiles::No;
+    }
+
+    let setting = repo.config()
+        .and_then(|c| c.get_string("status.showUntrackedFiles"))
+        .unwrap_or_default();
+    match setting.to_lowercase().as_str() {
+        "no" | "false" | "off" | "0" => UntrackedFiles::No,
+        "all" => UntrackedFiles::All,
+        _ => UntrackedFiles::Normal,
+    }
+}
+
+/// Counts the files in `repo` that have each kind of change, searching
+/// for untracked files as thoroughly as `untracked` says.
+///
+/// libgit2 can't make use of git's untracked cache, so repositories
+/// that turn on `core.untrackedCache` are handed to `git status`
+/// instead, as long as there are untracked files to search for.
+///
+/// Returns `None` if the status of the repository couldn't be read.
+fn summarize_statuses(repo: &Repository, untracked: UntrackedFiles) -> Option<StatusSummary> {
+    let untracked_cache = repo.config()
+        .and_then(|c| c.get_bool("core.untrackedCache"))
+        .unwrap_or(false);
+    if untracked_cache && untracked != UntrackedFiles::No {
+        if let Some(summary) = repo.workdir()
+               .and_then(|dir| summarize_statuses_with_git(dir, untracked)) {
+            return Some(summary);
         }
+    }
+
+    let mut status_options = StatusOptions::new();
+    status_options
+        .include_untracked(untracked != UntrackedFiles::No)
+        .recurse_untracked_dirs(untracked == UntrackedFiles::All)
+        .renames_head_to_index(true);
+
+    let statuses = repo.statuses(Some(&mut status_options)).ok()?;
+
+    let mut summary = StatusSummary::default();
+    for entry in statuses.iter() {
+        summary.add(entry.status());
+    }
+
+    Some(summary)
+}
+
+/// Counts the files in the repository at `workdir` that have each kind
+/// of change by running `git status`.
+///
+/// Returns `None` if git couldn't be run or failed.
+fn summarize_statuses_with_git(workdir: &Path, untracked: UntrackedFiles) -> Option<StatusSummary> {
+    let output = Command::new("git")
+        .current_dir(workdir)
+        // Don't fight with other git commands over the index
+        .env("GIT_OPTIONAL_LOCKS", "0")
+        .arg("status")
+        .arg("--porcelain")
+        .arg("-z")
+        .arg(format!("--untracked-files={}", untracked.name()))
+        .output()
+        .ok()?;
+
+    if output.status.success() {
+        Some(summarize_porcelain(&output.stdout))
     } else {
-        // Current working directory wasn't a git repository. Harmless
-        // error, return `None` so that we don't print anything, and
-        // just move on to the next module.
-        Ok(FormatResult::default())
+        None
+    }
+}
+
+/// Counts the files that have each kind of change in the output of
+/// `git status --porcelain -z`.
+fn summarize_porcelain(porcelain: &[u8]) -> StatusSummary {
+    let mut summary = StatusSummary::default();
+
+    let mut entries = porcelain.split(|&b| b == 0);
+    while let Some(entry) = entries.next() {
+        if entry.len() < 3 {
+            continue;
+        }
+
+        let (x, y) = (entry[0], entry[1]);
+
+        // Renames and copies are followed by the path they came from
+        if x == b'R' || x == b'C' {
+            entries.next();
+        }
+
+        summary.add(status_from_porcelain(x, y));
+    }
+
+    summary
+}
+
+/// Translates the two-letter status code used by `git status
+/// --porcelain` into a `Status`.
+fn status_from_porcelain(x: u8, y: u8) -> Status {
+    match (x, y) {
+        (b'D', b'D') | (b'A', b'U') | (b'U', b'D') | (b'U', b'A') | (b'D', b'U') |
+        (b'A', b'A') | (b'U', b'U') => return git2::STATUS_CONFLICTED,
+        (b'?', b'?') => return git2::STATUS_WT_NEW,
+        _ => {}
+    }
+
+    let index = match x {
+        b'M' => git2::STATUS_INDEX_MODIFIED,
+        b'A' | b'C' => git2::STATUS_INDEX_NEW,
+        b'D' => git2::STATUS_INDEX_DELETED,
+        b'R' => git2::STATUS_INDEX_RENAMED,
+        b'T' => git2::STATUS_INDEX_TYPECHANGE,
+        _ => Status::empty(),
+    };
+
+    let workdir = match y {
+        b'M' => git2::STATUS_WT_MODIFIED,
+        b'D' => git2::STATUS_WT_DELETED,
+        b'R' => git2::STATUS_WT_RENAMED,
+        b'T' => git2::STATUS_WT_TYPECHANGE,
+        _ => Status::empty(),
+    };
+
+    index | workdir
+}
+
+/// Finds how many commits the current branch of `repo` is ahead and
+/// behind its upstream.
+///
+/// Returns `None` if there's no upstream, or if errors are encountered
+/// AT ANY POINT.
+fn ahead_behind(repo: &Repository) -> Option<(usize, usize)> {
+    let local = Branch::wrap(repo.head().ok()?);
+    let upstream = local.upstream().ok()?;
+
+    let local_target = local.get().target()?;
+    let upstream_target = upstream.get().target()?;
+
+    repo.graph_ahead_behind(local_target, upstream_target).ok()
+}
+
+/// Counts the stashes in `repo`. Errors are treated as there being no
+/// stashes at all.
+fn count_stashes(repo: &mut Repository) -> usize {
+    let mut count = 0;
+    let _ = repo.stash_foreach(|_, _, _| {
+                                   count += 1;
+                                   true
+                               });
+    count
+}
+
+/// Describes the commit `oid` that a detached HEAD points to, as the
+/// name of its tag if it has one, or its abbreviated id otherwise.
+fn describe_detached(repo: &Repository, oid: Oid, git_options: &GitOptions) -> String {
+    // Only accept tags that point exactly at the commit
+    let mut describe_options = DescribeOptions::new();
+    describe_options.describe_tags().max_candidates_tags(0);
+
+    if let Ok(tag) = repo.describe(&describe_options)
+           .and_then(|d| d.format(None)) {
+        format!("{}{}", git_options.symbol_tag, tag)
+    } else {
+        let id = oid.to_string();
+        format!("{}{}", git_options.symbol_detached, &id[..7])
+    }
+}
+
+/// Describes the operation that's in progress in `repo`, like
+/// "MERGING" or "REBASE 3/7".
+///
+/// Returns `None` if nothing is in progress.
+fn describe_state(repo: &Repository) -> Option<String> {
+    let label = match repo.state() {
+        RepositoryState::Clean => return None,
+        RepositoryState::Merge => "MERGING",
+        RepositoryState::Revert |
+        RepositoryState::RevertSequence => "REVERTING",
+        RepositoryState::CherryPick |
+        RepositoryState::CherryPickSequence => "CHERRY-PICKING",
+        RepositoryState::Bisect => "BISECTING",
+        RepositoryState::Rebase |
+        RepositoryState::RebaseInteractive |
+        RepositoryState::RebaseMerge => "REBASE",
+        RepositoryState::ApplyMailbox => "AM",
+        RepositoryState::ApplyMailboxOrRebase => "AM/REBASE",
+    };
+
+    // Rebases keep track of how far along they are in the git
+    // directory. Interactive and merge-based rebases use
+    // `rebase-merge`, everything else uses `rebase-apply`.
+    let progress = rebase_progress(&repo.path().join("rebase-merge"), "msgnum", "end")
+        .or_else(|| rebase_progress(&repo.path().join("rebase-apply"), "next", "last"));
+
+    Some(match progress {
+             Some((step, total)) => format!("{} {}/{}", label, step, total),
+             None => label.to_string(),
+         })
+}
+
+/// Reads the current step and total number of steps of a rebase from
+/// the files `step` and `total` in `dir`.
+///
+/// Returns `None` if either file is missing or doesn't contain a
+/// number.
+fn rebase_progress(dir: &Path, step: &str, total: &str) -> Option<(u32, u32)> {
+    let read = |name: &str| {
+        fs::read_to_string(dir.join(name))
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+    };
+
+    Some((read(step)?, read(total)?))
+}
+
+/// Finds the repository that `path` belongs to, searching upwards
+/// through its parent directories like git does.
+///
+/// `$GIT_DIR` (along with `$GIT_WORK_TREE`) takes precedence over the
+/// search, and the search never enters any of the directories listed
+/// in `$GIT_CEILING_DIRECTORIES`. Working trees whose `.git` is a file
+/// pointing to the actual repository are supported too.
+///
+/// Returns `None` if `path` isn't part of a repository.
+pub fn discover_repository(path: &Path, env: &Environment) -> Option<Repository> {
+    if let Some(git_dir) = env.var("GIT_DIR") {
+        let repo = Repository::open(path.join(git_dir)).ok()?;
+        if let Some(work_tree) = env.var("GIT_WORK_TREE") {
+            repo.set_workdir(&path.join(work_tree), false).ok()?;
+        }
+        return Some(repo);
+    }
+
+    let ceiling_dirs: Vec<PathBuf> = if let Some(dirs) = env.var("GIT_CEILING_DIRECTORIES") {
+        env::split_paths(dirs)
+            .filter(|d| !d.as_os_str().is_empty())
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    open_repository(path, &ceiling_dirs)
+}
+
+/// Opens the repository containing `path` without searching any of
+/// the `ceiling_dirs`.
+fn open_repository(path: &Path, ceiling_dirs: &[PathBuf]) -> Option<Repository> {
+    Repository::open_ext(path, RepositoryOpenFlags::empty(), ceiling_dirs).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use ansi_term::{Color, Style};
+    use clap::Shell;
+    use git2::{BranchType, Signature};
+    use tempdir::TempDir;
+
+    use layout::{format_for_module, Neighbours, Side};
+
+    /// Stages `paths` (relative to the root of `repo`) and commits them
+    fn commit(repo: &Repository, paths: &[&str]) {
+        let mut index = repo.index().unwrap();
+        for path in paths {
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = Signature::now("contrail", "contrail@example.com").unwrap();
+        let parent = repo.head()
+            .ok()
+            .and_then(|h| h.target())
+            .map(|oid| repo.find_commit(oid).unwrap());
+        let parents: Vec<_> = parent.iter().collect();
+
+        repo.commit(Some("HEAD"), &sig, &sig, "commit", &tree, &parents)
+            .unwrap();
+    }
+
+    /// Joins the text of every segment together
+    fn text(segments: &[Segment]) -> String {
+        segments.iter().map(|s| s.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_open_repository_from_subdirectory() {
+        let dir = TempDir::new("contrail").unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let nested = root.join("src").join("modules");
+        fs::create_dir_all(&nested).unwrap();
+
+        Repository::init(&root).unwrap();
+
+        // Found from the root and from anywhere below it
+        for path in &[&root, &nested] {
+            let repo = open_repository(path, &[]).unwrap();
+            assert_eq!(repo.workdir().unwrap().canonicalize().unwrap(), root);
+        }
+
+        // The search stops before entering a ceiling directory
+        assert!(open_repository(&nested, &[root.clone()]).is_none());
+
+        // Not part of a repository at all
+        let outside = TempDir::new("contrail").unwrap();
+        assert!(open_repository(outside.path(), &[]).is_none());
+    }
+
+    #[test]
+    fn test_open_repository_from_gitlink() {
+        // A working tree whose `.git` is a file pointing somewhere
+        // else, like submodules and linked worktrees have
+        let dir = TempDir::new("contrail").unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let git_dir = root.join("actual.git");
+        let work = root.join("work");
+        fs::create_dir_all(work.join("sub")).unwrap();
+
+        Repository::init_bare(&git_dir).unwrap();
+        fs::write(work.join(".git"),
+                  format!("gitdir: {}\n", git_dir.display()))
+                .unwrap();
+
+        let repo = open_repository(&work.join("sub"), &[]).unwrap();
+        assert_eq!(repo.path().canonicalize().unwrap(), git_dir);
+    }
+
+    #[test]
+    fn test_git_options() {
+        let mut c = Config::new();
+
+        // Only the operation in progress and the dirty marker are shown
+        // by default
+        let options = GitOptions::read(&c).unwrap();
+        assert_eq!(options.format,
+                   Template::parse("{branch}{state?}{dirty?}{timeout?}{ahead?}{behind?}").unwrap());
+
+        c.set("modules.git.show_dirty", false).unwrap();
+        c.set("modules.git.show_untracked", true).unwrap();
+        c.set("modules.git.show_staged", true).unwrap();
+        c.set("modules.git.symbol_staged", "S").unwrap();
+        c.set("modules.git.style_staged.foreground", "green")
+            .unwrap();
+        let options = GitOptions::read(&c).unwrap();
+        assert_eq!(options.format,
+                   Template::parse("{branch}{state?}{timeout?}{staged?}{untracked?}{ahead?}{behind?}")
+                       .unwrap());
+        assert_eq!(options.counters[StatusKind::Staged as usize],
+                   Counter {
+                       kind: StatusKind::Staged,
+                       symbol: String::from("S"),
+                       style: ModuleStyle {
+                           foreground: Some(Color::Green),
+                           ..ModuleStyle::default()
+                       },
+                   });
+
+        // An explicit format takes precedence over the toggles
+        c.set("modules.git.format", "{branch}{ahead? ⇡{ahead}}")
+            .unwrap();
+        let options = GitOptions::read(&c).unwrap();
+        assert_eq!(options.format.variables(), vec!["branch", "ahead", "ahead"]);
+
+        c.set("modules.git.format", "{branch}{typo?}").unwrap();
+        assert!(GitOptions::read(&c).is_err());
+
+        c.set("modules.git.format", "{branch").unwrap();
+        assert!(GitOptions::read(&c).is_err());
+
+        c.set("modules.git.format", "{branch}").unwrap();
+        c.set("modules.git.show_deleted", "yes").unwrap();
+        assert!(GitOptions::read(&c).is_ok());
+        c.set("modules.git.format", Vec::<String>::new()).unwrap();
+        assert!(GitOptions::read(&c).is_err());
+    }
+
+    #[test]
+    fn test_format_repository() {
+        let dir = TempDir::new("contrail").unwrap();
+        let root = dir.path();
+        let mut repo = Repository::init(root).unwrap();
+
+        // No HEAD yet, so nothing to show
+        let mut c = Config::new();
+        assert!(format_repository(&mut repo, &GitOptions::read(&c).unwrap(), None).is_empty());
+
+        for name in &["modified", "deleted", "renamed", "staged"] {
+            fs::write(root.join(name), name).unwrap();
+        }
+        commit(&repo, &["modified", "deleted", "renamed", "staged"]);
+        assert_eq!(text(&format_repository(&mut repo, &GitOptions::read(&c).unwrap(), None)),
+                   "master");
+
+        fs::write(root.join("modified"), "changed").unwrap();
+        fs::remove_file(root.join("deleted")).unwrap();
+        fs::write(root.join("untracked"), "").unwrap();
+        fs::write(root.join("staged"), "changed").unwrap();
+        fs::rename(root.join("renamed"), root.join("moved")).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("staged")).unwrap();
+        index.remove_path(Path::new("renamed")).unwrap();
+        index.add_path(Path::new("moved")).unwrap();
+        index.write().unwrap();
+
+        let summary = summarize_statuses(&repo, UntrackedFiles::Normal).unwrap();
+        assert!(summary.dirty);
+        assert_eq!(summary.count(StatusKind::Conflicted), 0);
+        assert_eq!(summary.count(StatusKind::Staged), 2);
+        assert_eq!(summary.count(StatusKind::Modified), 1);
+        assert_eq!(summary.count(StatusKind::Renamed), 1);
+        assert_eq!(summary.count(StatusKind::Deleted), 1);
+        assert_eq!(summary.count(StatusKind::Untracked), 1);
+
+        // Untracked files are only searched for when they're shown
+        assert_eq!(summarize_statuses(&repo, UntrackedFiles::No)
+                       .unwrap()
+                       .count(StatusKind::Untracked),
+                   0);
+
+        assert_eq!(text(&format_repository(&mut repo, &GitOptions::read(&c).unwrap(), None)),
+                   "master +");
+
+        for kind in &STATUS_KINDS {
+            c.set(&format!("modules.git.show_{}", kind.name()), true)
+                .unwrap();
+        }
+        c.set("modules.git.style_modified.foreground", "red")
+            .unwrap();
+        let segments = format_repository(&mut repo, &GitOptions::read(&c).unwrap(), None);
+        assert_eq!(text(&segments), "master + ●2 !1 »1 ✘1 ?1");
+        assert_eq!(segments[3],
+                   Segment::styled(" !1",
+                                ModuleStyle {
+                                    foreground: Some(Color::Red),
+                                    ..ModuleStyle::default()
+                                }));
+    }
+
+    #[test]
+    fn test_describe_state() {
+        let dir = TempDir::new("contrail").unwrap();
+        let root = dir.path();
+        let mut repo = Repository::init(root).unwrap();
+        fs::write(root.join("file"), "").unwrap();
+        commit(&repo, &["file"]);
+        let head = repo.head().unwrap().target().unwrap().to_string();
+        let git_dir = repo.path().to_path_buf();
+
+        assert_eq!(describe_state(&repo), None);
+
+        fs::write(git_dir.join("MERGE_HEAD"), &head).unwrap();
+        assert_eq!(describe_state(&repo), Some(String::from("MERGING")));
+        fs::remove_file(git_dir.join("MERGE_HEAD")).unwrap();
+
+        fs::write(git_dir.join("CHERRY_PICK_HEAD"), &head).unwrap();
+        assert_eq!(describe_state(&repo),
+                   Some(String::from("CHERRY-PICKING")));
+        fs::remove_file(git_dir.join("CHERRY_PICK_HEAD")).unwrap();
+
+        // Interactive rebases show how far along they are
+        let rebase_dir = git_dir.join("rebase-merge");
+        fs::create_dir(&rebase_dir).unwrap();
+        fs::write(rebase_dir.join("interactive"), "").unwrap();
+        assert_eq!(describe_state(&repo), Some(String::from("REBASE")));
+        fs::write(rebase_dir.join("msgnum"), "3\n").unwrap();
+        fs::write(rebase_dir.join("end"), "7\n").unwrap();
+        assert_eq!(describe_state(&repo), Some(String::from("REBASE 3/7")));
+
+        let mut c = Config::new();
+        c.set("modules.git.style_state.foreground", "yellow")
+            .unwrap();
+        let segments = format_repository(&mut repo, &GitOptions::read(&c).unwrap(), None);
+        assert_eq!(segments[1],
+                   Segment::styled(" REBASE 3/7",
+                                ModuleStyle {
+                                    foreground: Some(Color::Yellow),
+                                    ..ModuleStyle::default()
+                                }));
+
+        c.set("modules.git.show_state", false).unwrap();
+        assert_eq!(text(&format_repository(&mut repo, &GitOptions::read(&c).unwrap(), None)),
+                   "master");
+    }
+
+    #[test]
+    fn test_describe_detached() {
+        let dir = TempDir::new("contrail").unwrap();
+        let root = dir.path();
+        let mut repo = Repository::init(root).unwrap();
+        fs::write(root.join("file"), "").unwrap();
+        commit(&repo, &["file"]);
+        fs::write(root.join("file"), "changed").unwrap();
+        commit(&repo, &["file"]);
+
+        let oid = repo.head().unwrap().target().unwrap();
+        let id = oid.to_string();
+        repo.set_head_detached(oid).unwrap();
+
+        let mut c = Config::new();
+        c.set("modules.git.style_detached.foreground", "purple")
+            .unwrap();
+        let segments = format_repository(&mut repo, &GitOptions::read(&c).unwrap(), None);
+        assert_eq!(segments[0],
+                   Segment::styled(format!("@{}", &id[..7]),
+                                ModuleStyle {
+                                    foreground: Some(Color::Purple),
+                                    ..ModuleStyle::default()
+                                }));
+
+        // Tags are only used when they point at HEAD itself
+        let sig = Signature::now("contrail", "contrail@example.com").unwrap();
+        let parent = repo.find_commit(oid).unwrap().parent_id(0).unwrap();
+        repo.tag("v0.1",
+                 &repo.find_object(parent, None).unwrap(),
+                 &sig,
+                 "v0.1",
+                 false)
+            .unwrap();
+        assert_eq!(text(&format_repository(&mut repo, &GitOptions::read(&c).unwrap(), None)),
+                   format!("@{}", &id[..7]));
+
+        repo.tag("v0.2",
+                 &repo.find_object(oid, None).unwrap(),
+                 &sig,
+                 "v0.2",
+                 false)
+            .unwrap();
+        c.set("modules.git.symbol_tag", "tag ").unwrap();
+        assert_eq!(text(&format_repository(&mut repo, &GitOptions::read(&c).unwrap(), None)),
+                   "tag v0.2");
+    }
+
+    #[test]
+    fn test_count_stashes() {
+        let dir = TempDir::new("contrail").unwrap();
+        let root = dir.path();
+        let mut repo = Repository::init(root).unwrap();
+        fs::write(root.join("file"), "").unwrap();
+        commit(&repo, &["file"]);
+
+        assert_eq!(count_stashes(&mut repo), 0);
+
+        let sig = Signature::now("contrail", "contrail@example.com").unwrap();
+        for contents in &["first", "second"] {
+            fs::write(root.join("file"), contents).unwrap();
+            repo.stash_save(&sig, contents, None).unwrap();
+        }
+        assert_eq!(count_stashes(&mut repo), 2);
+
+        // Stashes are only counted when they're shown
+        let mut c = Config::new();
+        assert_eq!(text(&format_repository(&mut repo, &GitOptions::read(&c).unwrap(), None)),
+                   "master");
+
+        c.set("modules.git.show_stash", true).unwrap();
+        c.set("modules.git.style_stash.foreground", "cyan")
+            .unwrap();
+        let segments = format_repository(&mut repo, &GitOptions::read(&c).unwrap(), None);
+        assert_eq!(segments[1],
+                   Segment::styled(" $2",
+                                ModuleStyle {
+                                    foreground: Some(Color::Cyan),
+                                    ..ModuleStyle::default()
+                                }));
+    }
+
+    #[test]
+    fn test_format_repository_template() {
+        let dir = TempDir::new("contrail").unwrap();
+        let root = dir.path();
+        let mut repo = Repository::init(root).unwrap();
+        fs::write(root.join("tracked"), "").unwrap();
+        commit(&repo, &["tracked"]);
+        fs::write(root.join("tracked"), "changed").unwrap();
+        fs::write(root.join("untracked"), "").unwrap();
+
+        let mut c = Config::new();
+        c.set("modules.git.format",
+              "[{branch}]{dirty? *}{untracked? ({untracked} new)}{stash?}")
+            .unwrap();
+        c.set("modules.git.style_untracked.foreground", "red")
+            .unwrap();
+        let segments = format_repository(&mut repo, &GitOptions::read(&c).unwrap(), None);
+        assert_eq!(text(&segments), "[master] * (1 new)");
+
+        // Sections take the style of the variable they depend on
+        let red = ModuleStyle {
+            foreground: Some(Color::Red),
+            ..ModuleStyle::default()
+        };
+        assert_eq!(&segments[4..],
+                   &[Segment::styled(" (", red),
+                     Segment::styled("1", red),
+                     Segment::styled(" new)", red)]);
+    }
+
+    #[test]
+    fn test_format_repository_part_styles() {
+        let dir = TempDir::new("contrail").unwrap();
+        let root = dir.path();
+        let mut repo = Repository::init(root).unwrap();
+        fs::write(root.join("tracked"), "").unwrap();
+        commit(&repo, &["tracked"]);
+        fs::write(root.join("tracked"), "changed").unwrap();
+
+        let mut c = Config::new();
+        c.set("modules.git.style_branch.foreground", "blue")
+            .unwrap();
+        c.set("modules.git.style_dirty.foreground", "red")
+            .unwrap();
+        c.set("modules.git.style_detached.text_properties",
+              vec!["bold"])
+            .unwrap();
+        let segments = format_repository(&mut repo, &GitOptions::read(&c).unwrap(), None);
+        let blue = ModuleStyle {
+            foreground: Some(Color::Blue),
+            ..ModuleStyle::default()
+        };
+        let red = ModuleStyle {
+            foreground: Some(Color::Red),
+            ..ModuleStyle::default()
+        };
+        assert_eq!(segments,
+                   vec![Segment::styled("master", blue), Segment::styled(" +", red)]);
+
+        // A detached HEAD is styled on top of the branch style
+        let oid = repo.head().unwrap().target().unwrap();
+        repo.set_head_detached(oid).unwrap();
+        let segments = format_repository(&mut repo, &GitOptions::read(&c).unwrap(), None);
+        assert_eq!(segments[0].style,
+                   ModuleStyle {
+                       foreground: Some(Color::Blue),
+                       text_properties: Some(Style::new().bold()),
+                       ..ModuleStyle::default()
+                   });
+
+        // Every segment is escaped on its own within the one module
+        let options = modules::read_options("git", &c).unwrap();
+        let formatted = format_for_module(segments,
+                                          &options,
+                                          Neighbours::default(),
+                                          Side::Left,
+                                          Shell::Zsh);
+        assert_eq!(format!("{}", formatted).matches("%{").count(), 4);
+    }
+
+    #[test]
+    fn test_untracked_files() {
+        let dir = TempDir::new("contrail").unwrap();
+        let root = dir.path();
+        let repo = Repository::init(root).unwrap();
+        fs::create_dir(root.join("new")).unwrap();
+        fs::write(root.join("new/a"), "").unwrap();
+        fs::write(root.join("new/b"), "").unwrap();
+
+        assert_eq!(untracked_files(&repo, true), UntrackedFiles::Normal);
+        assert_eq!(untracked_files(&repo, false), UntrackedFiles::No);
+        let count = |untracked| {
+            summarize_statuses(&repo, untracked)
+                .unwrap()
+                .count(StatusKind::Untracked)
+        };
+        assert_eq!(count(UntrackedFiles::Normal), 1);
+        assert_eq!(count(UntrackedFiles::All), 2);
+
+        let mut config = repo.config().unwrap();
+        config
+            .set_str("status.showUntrackedFiles", "all")
+            .unwrap();
+        assert_eq!(untracked_files(&repo, true), UntrackedFiles::All);
+        config.set_str("status.showUntrackedFiles", "no").unwrap();
+        assert_eq!(untracked_files(&repo, true), UntrackedFiles::No);
+
+        // Repositories using the untracked cache are counted the same
+        // way, whether or not git itself is available to do it
+        config.set_bool("core.untrackedCache", true).unwrap();
+        assert_eq!(count(UntrackedFiles::Normal), 1);
+        assert_eq!(count(UntrackedFiles::All), 2);
+    }
+
+    #[test]
+    fn test_summarize_porcelain() {
+        let porcelain = b"M  staged\0 M modified\0MM both\0R  new\0old\0 D deleted\0\
+                          UU conflicted\0?? untracked/\0";
+        let summary = summarize_porcelain(porcelain);
+        assert!(summary.dirty);
+        assert_eq!(summary.counts, [1, 3, 2, 1, 1, 1]);
+
+        // Nothing but staged changes
+        let summary = summarize_porcelain(b"A  new\0");
+        assert!(!summary.dirty);
+        assert_eq!(summary.counts, [0, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_format_repository_timeout() {
+        let dir = TempDir::new("contrail").unwrap();
+        let root = dir.path();
+        let mut repo = Repository::init(root).unwrap();
+        fs::write(root.join("tracked"), "").unwrap();
+        commit(&repo, &["tracked"]);
+        fs::write(root.join("tracked"), "changed").unwrap();
+
+        let mut c = Config::new();
+        c.set("modules.git.timeout", 10000).unwrap();
+        let mut options = GitOptions::read(&c).unwrap();
+        assert_eq!(options.timeout, Some(Duration::from_millis(10000)));
+        assert_eq!(text(&format_repository(&mut repo, &options, None)), "master +");
+
+        // Out of time, so the dirty check is replaced by a marker
+        options.timeout = Some(Duration::default());
+        assert_eq!(text(&format_repository(&mut repo, &options, None)), "master ?");
+
+        // Even a format without a place for the marker gets one
+        c.set("modules.git.format", "{branch}{dirty?}").unwrap();
+        let mut options = GitOptions::read(&c).unwrap();
+        options.timeout = Some(Duration::default());
+        assert_eq!(text(&format_repository(&mut repo, &options, None)), "master ?");
+
+        // A repository that can't be opened again doesn't count as
+        // clean
+        let query = SlowQuery {
+            status: true,
+            untracked: UntrackedFiles::No,
+            ahead_behind: false,
+        };
+        fs::remove_dir_all(root.join(".git")).unwrap();
+        assert_eq!(compute_slow_parts_within(&repo, query, Duration::from_secs(10)),
+                   None);
+
+        c.set("modules.git.timeout", -1).unwrap();
+        assert!(GitOptions::read(&c).is_err());
+        c.set("modules.git.timeout", 0).unwrap();
+        assert_eq!(GitOptions::read(&c).unwrap().timeout, None);
+    }
+
+    #[test]
+    fn test_git_cache() {
+        let dir = TempDir::new("contrail").unwrap();
+        let root = dir.path();
+        let mut repo = Repository::init(root).unwrap();
+        fs::write(root.join("tracked"), "").unwrap();
+        commit(&repo, &["tracked"]);
+
+        let cache = GitCache::default();
+        let mut options = GitOptions::read(&Config::new()).unwrap();
+        assert_eq!(options.cache_ttl, None);
+        assert_eq!(text(&format_repository(&mut repo, &options, Some(&cache))),
+                   "master");
+
+        // By default, the status is reused until the repository
+        // changes, so edits to files go unnoticed
+        fs::write(root.join("tracked"), "changed").unwrap();
+        assert_eq!(text(&format_repository(&mut repo, &options, Some(&cache))),
+                   "master");
+        assert_eq!(text(&format_repository(&mut repo, &options, None)),
+                   "master +");
+
+        // Unless `cache_ttl` runs out first
+        options.cache_ttl = Some(Duration::default());
+        assert_eq!(text(&format_repository(&mut repo, &options, Some(&cache))),
+                   "master +");
+        options.cache_ttl = Some(Duration::from_secs(60));
+        fs::write(root.join("tracked"), "").unwrap();
+        assert_eq!(text(&format_repository(&mut repo, &options, Some(&cache))),
+                   "master +");
+
+        // Changes to the index count
+        let index = fs::read(repo.path().join("index")).unwrap();
+        fs::remove_file(repo.path().join("index")).unwrap();
+        assert_eq!(text(&format_repository(&mut repo, &options, Some(&cache))),
+                   "master");
+        fs::write(repo.path().join("index"), index).unwrap();
+
+        // Asking for something else doesn't reuse the entry either
+        let query = SlowQuery {
+            status: true,
+            untracked: UntrackedFiles::Normal,
+            ahead_behind: true,
+        };
+        assert_eq!(cache.scan(&repo, query, None),
+                   Some(compute_slow_parts(&repo, query)));
+        assert_eq!(cache.get(&repo, query, None),
+                   Some(compute_slow_parts(&repo, query)));
+        assert_eq!(cache.get(&repo,
+                             SlowQuery {
+                                 untracked: UntrackedFiles::No,
+                                 ..query
+                             },
+                             None),
+                   None);
+        assert!(cache.scans.lock().unwrap().is_empty());
+
+        // Without room for another scan, there's nothing to show
+        let query = SlowQuery {
+            status: true,
+            untracked: UntrackedFiles::No,
+            ahead_behind: true,
+        };
+        fs::write(root.join("tracked"), "changed").unwrap();
+        options.timeout = Some(Duration::default());
+        options.cache_ttl = Some(Duration::default());
+        let cache = GitCache::default();
+        for i in 0..MAX_SCANS {
+            cache
+                .scans
+                .lock()
+                .unwrap()
+                .insert((PathBuf::from(i.to_string()), query), Vec::new());
+        }
+        assert_eq!(cache.scan(&repo, query, None), None);
+        assert_eq!(text(&format_repository(&mut repo, &options, Some(&cache))),
+                   "master ?");
+
+        // Otherwise scans that run out of time keep going, and are
+        // shown next time along with the marker
+        let cache = GitCache::default();
+        format_repository(&mut repo, &options, Some(&cache));
+        for _ in 0..100 {
+            if cache.last(&repo, query).is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(cache.last(&repo, query),
+                   Some(compute_slow_parts(&repo, query)));
+        assert_eq!(text(&format_repository(&mut repo, &options, Some(&cache))),
+                   "master + ?");
+    }
+
+    #[test]
+    fn test_repository_stamp() {
+        let dir = TempDir::new("contrail").unwrap();
+        let root = dir.path();
+        let repo = Repository::init(root).unwrap();
+        fs::write(root.join("tracked"), "").unwrap();
+        commit(&repo, &["tracked"]);
+        let first = repo.head().unwrap().target().unwrap();
+        commit(&repo, &["tracked"]);
+        let second = repo.head().unwrap().target().unwrap();
+
+        // Moving the branch, like committing does
+        let stamp = RepositoryStamp::read(&repo);
+        repo.reference("refs/heads/master", first, true, "reset")
+            .unwrap();
+        assert!(RepositoryStamp::read(&repo) != stamp);
+
+        // Moving its upstream, like fetching does
+        repo.reference("refs/remotes/origin/master", first, true, "fetch")
+            .unwrap();
+        repo.remote("origin", "https://example.com/repo.git")
+            .unwrap();
+        repo.find_branch("master", BranchType::Local)
+            .unwrap()
+            .set_upstream(Some("origin/master"))
+            .unwrap();
+        let stamp = RepositoryStamp::read(&repo);
+        repo.reference("refs/remotes/origin/master", second, true, "fetch")
+            .unwrap();
+        assert!(RepositoryStamp::read(&repo) != stamp);
+    }
+
+    #[test]
+    fn test_repository_stamp_worktree() {
+        let dir = TempDir::new("contrail").unwrap();
+        let root = dir.path().join("main");
+        let repo = Repository::init(&root).unwrap();
+        fs::write(root.join("tracked"), "").unwrap();
+        commit(&repo, &["tracked"]);
+        let first = repo.head().unwrap().target().unwrap();
+        commit(&repo, &["tracked"]);
+
+        let status = Command::new("git")
+            .current_dir(&root)
+            .arg("worktree")
+            .arg("add")
+            .arg("-q")
+            .arg("-b")
+            .arg("linked")
+            .arg(dir.path().join("linked"))
+            .status()
+            .expect("git isn't installed");
+        assert!(status.success());
+        let linked = Repository::open(dir.path().join("linked")).unwrap();
+
+        // The refs live in the main repository
+        assert_eq!(common_dir(linked.path()).canonicalize().unwrap(),
+                   repo.path().canonicalize().unwrap());
+        assert_eq!(common_dir(repo.path()), repo.path());
+
+        // So moving the branch from there counts too
+        let stamp = RepositoryStamp::read(&linked);
+        repo.reference("refs/heads/linked", first, true, "reset")
+            .unwrap();
+        assert!(RepositoryStamp::read(&linked) != stamp);
+    }
+
+    #[test]
+    fn test_remember() {
+        let mut entries = HashMap::new();
+        let query = SlowQuery {
+            status: true,
+            untracked: UntrackedFiles::No,
+            ahead_behind: false,
+        };
+        let start = Instant::now();
+
+        for i in 0..MAX_CACHE_ENTRIES + 1 {
+            remember(&mut entries,
+                     PathBuf::from(i.to_string()),
+                     CacheEntry {
+                         query: query,
+                         stamp: RepositoryStamp { modified: Vec::new() },
+                         created: start + Duration::from_millis(i as u64),
+                         slow_parts: SlowParts::default(),
+                     });
+        }
+
+        // The oldest entry made room for the newest one
+        assert_eq!(entries.len(), MAX_CACHE_ENTRIES);
+        assert!(!entries.contains_key(Path::new("0")));
+        assert!(entries.contains_key(Path::new("1")));
+        assert!(entries.contains_key(&PathBuf::from(MAX_CACHE_ENTRIES.to_string())));
     }
 }
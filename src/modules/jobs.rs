@@ -0,0 +1,64 @@
+use utils::{Error, FormatResult};
+
+use modules::{self, Context, Module, Segment};
+
+/// Shows the number of jobs running in the background, if there are
+/// any.
+pub struct Jobs;
+
+impl Module for Jobs {
+    fn name(&self) -> &str {
+        "jobs"
+    }
+
+    /// Formats the number of background jobs using whatever options are
+    /// present in the config file provided. Nothing is shown if there
+    /// aren't any jobs.
+    ///
+    /// Returns an `Error` if it encounters any errors while parsing the
+    /// config file.
+    fn render(&self, ctx: &Context) -> Result<FormatResult, Error> {
+        let options = modules::read_options("jobs", ctx.config)?;
+
+        if ctx.jobs == 0 {
+            return Ok(FormatResult::default());
+        }
+
+        let format_result = FormatResult {
+            output: Some(vec![Segment::new(ctx.jobs.to_string())]),
+            options: options,
+        };
+
+        Ok(format_result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ansi_term::Color;
+    use config::Config;
+
+    use modules::Environment;
+
+    #[test]
+    fn test_format_jobs() {
+        let mut c = Config::new();
+        let env = Environment::default();
+
+        c.set("modules.jobs.style.background", "yellow").unwrap();
+
+        let mut ctx = Context::for_test(&c, &env);
+
+        // No jobs == nothing to print
+        let result = Jobs.render(&ctx).unwrap();
+        assert_eq!(result.output, None);
+        assert_eq!(result.options.style.background, None);
+
+        ctx.jobs = 2;
+        let result = Jobs.render(&ctx).unwrap();
+        assert_eq!(result.options.style.background, Some(Color::Yellow));
+        assert_eq!(result.output, Some(vec![Segment::new("2")]));
+    }
+}
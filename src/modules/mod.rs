@@ -1,22 +1,126 @@
+use std::collections::HashMap;
 use std::default::Default;
+use std::env;
+use std::path::PathBuf;
 
-use ansi_term::{ANSIString, Color, Style};
+use ansi_term::{Color, Style};
 use config::{Config, Value};
-use clap::Shell;
+use unicode_width::UnicodeWidthStr;
 
-use utils::{Error, ErrorKind};
+use utils::{Error, ErrorKind, FormatResult};
 
 mod cwd;
+mod duration;
 mod exit_code;
+mod fallback;
 mod generic;
 mod git;
+mod jobs;
 mod prompt;
+mod registry;
+mod template;
 
 pub use self::cwd::*;
+pub use self::duration::*;
 pub use self::exit_code::*;
+pub use self::fallback::*;
 pub use self::generic::*;
 pub use self::git::*;
+pub use self::jobs::*;
 pub use self::prompt::*;
+pub use self::registry::*;
+pub use self::template::*;
+
+/// Information about the shell and the config file that every module
+/// has access to while rendering
+pub struct Context<'a> {
+    /// The user's config file
+    pub config: &'a Config,
+    /// Exit code of the last-executed command
+    pub exit_code: u8,
+    /// Number of jobs running in the background
+    pub jobs: u32,
+    /// Time taken by the last-executed command, in milliseconds
+    pub duration: u64,
+    /// Directory and environment variables of the shell
+    pub env: &'a Environment,
+    /// Git status remembered from earlier prompts, when running as a
+    /// daemon
+    pub git_cache: Option<&'a GitCache>,
+}
+
+/// Environment variables that modules are allowed to read. Nothing
+/// else is captured in an `Environment` (or sent to the daemon).
+pub const ENV_VARS: [&str; 6] = ["PWD",
+                                 "HOME",
+                                 "COLUMNS",
+                                 "GIT_DIR",
+                                 "GIT_WORK_TREE",
+                                 "GIT_CEILING_DIRECTORIES"];
+
+/// The working directory and environment variables of the shell that
+/// the prompt is drawn for.
+///
+/// Modules read these instead of looking at the process itself, since
+/// the daemon draws prompts for many shells at once.
+#[derive(Debug, Default, PartialEq)]
+pub struct Environment {
+    /// The working directory, if it still exists
+    pub current_dir: Option<PathBuf>,
+    /// Values of the variables in `ENV_VARS` that are set
+    pub vars: HashMap<String, String>,
+}
+
+impl Environment {
+    /// Captures the environment of this process.
+    pub fn current() -> Environment {
+        Environment {
+            current_dir: env::current_dir().ok(),
+            vars: ENV_VARS
+                .iter()
+                .filter_map(|&name| env::var(name).ok().map(|val| (name.to_string(), val)))
+                .collect(),
+        }
+    }
+
+    /// Gets the value of an environment variable, as long as it's set
+    /// and listed in `ENV_VARS`.
+    pub fn var(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+impl<'a> Context<'a> {
+    /// Creates a `Context` for trying out a module with `config` in
+    /// `env`, as if the last command succeeded, took no time at all and
+    /// left no jobs behind.
+    pub fn for_test(config: &'a Config, env: &'a Environment) -> Context<'a> {
+        Context {
+            config: config,
+            exit_code: 0,
+            jobs: 0,
+            duration: 0,
+            env: env,
+            git_cache: None,
+        }
+    }
+}
+
+/// A part of the prompt that can be listed in `global.modules`.
+///
+/// Every module is rendered on its own thread, at the same time as
+/// all the others.
+pub trait Module: Sync {
+    /// The name the module is referred to by in the config file
+    fn name(&self) -> &str;
+
+    /// Renders the content of the module using the given `Context`.
+    ///
+    /// Returns an `Error` if it encounters any errors while parsing
+    /// the config file.
+    fn render(&self, ctx: &Context) -> Result<FormatResult, Error>;
+}
 
 /// Representation of config options that all modules have
 #[derive(Debug, PartialEq)]
@@ -29,6 +133,20 @@ pub struct ModuleOptions {
     pub padding_right: String,
     /// String to print out after the content and right padding
     pub separator: String,
+    /// String to print out before the left padding and content
+    pub separator_left: String,
+    /// String that replaces `separator` on the right side of the line,
+    /// where it's drawn in front of the module
+    pub separator_right: String,
+    /// String that, if present, replaces `separator` when the next
+    /// module has the same background color
+    pub separator_thin: Option<String>,
+    /// Modules with a lower priority are dropped first when the prompt
+    /// doesn't fit on the line
+    pub priority: i64,
+    /// If present, the module is shrunk down to this many columns
+    /// before it's dropped
+    pub min_width: Option<usize>,
     /// Background color, foreground color, etc.
     pub style: ModuleStyle,
 }
@@ -42,13 +160,18 @@ impl Default for ModuleOptions {
             padding_left: String::from(" "),
             padding_right: String::from(" "),
             separator: String::from(""),
+            separator_left: String::from(""),
+            separator_right: String::from(""),
+            separator_thin: None,
+            priority: 0,
+            min_width: None,
             style: ModuleStyle::default(),
         }
     }
 }
 
 /// Representation of how to style a module
-#[derive(Debug, Default, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct ModuleStyle {
     /// Color behind the text
     pub background: Option<Color>,
@@ -58,6 +181,51 @@ pub struct ModuleStyle {
     pub text_properties: Option<Style>,
 }
 
+impl ModuleStyle {
+    /// Returns a copy of this style, with anything that isn't set
+    /// taken from `fallback` instead.
+    pub fn or(&self, fallback: &ModuleStyle) -> ModuleStyle {
+        ModuleStyle {
+            background: self.background.or(fallback.background),
+            foreground: self.foreground.or(fallback.foreground),
+            text_properties: self.text_properties.or(fallback.text_properties),
+        }
+    }
+}
+
+/// A piece of a module's content that can be styled differently from
+/// the rest of the module
+#[derive(Debug, Default, PartialEq)]
+pub struct Segment {
+    /// The text to display
+    pub text: String,
+    /// Anything set here overrides the style of the module
+    pub style: ModuleStyle,
+}
+
+impl Segment {
+    /// Creates a `Segment` that's styled like the rest of the module.
+    pub fn new<S: Into<String>>(text: S) -> Segment {
+        Segment {
+            text: text.into(),
+            style: ModuleStyle::default(),
+        }
+    }
+
+    /// Creates a `Segment` with its own style.
+    pub fn styled<S: Into<String>>(text: S, style: ModuleStyle) -> Segment {
+        Segment {
+            text: text.into(),
+            style: style,
+        }
+    }
+}
+
+/// Number of columns `segments` take up on the terminal
+pub fn segments_width(segments: &[Segment]) -> usize {
+    segments.iter().map(|segment| segment.text.width()).sum()
+}
+
 /// Turns a `Value` into a `String` or returns an `Error` if the
 /// `Value` wasn't a `String` to begin with.
 ///
@@ -99,10 +267,26 @@ pub fn read_options(key: &str, config: &Config) -> Result<ModuleOptions, Error>
         String::from(" ")
     };
 
-    let separator = if let Some(val) = config.get(&format!("modules.{}.separator", key)) {
-        unwrap_value_if_string(val)?
+    let separator = read_string(&format!("modules.{}.separator", key), "", config)?;
+    let separator_left = read_string(&format!("modules.{}.separator_left", key), "", config)?;
+    let separator_right = read_string(&format!("modules.{}.separator_right", key), "", config)?;
+
+    let thin_key = format!("modules.{}.separator_thin", key);
+    let separator_thin = if config.get(&thin_key).is_some() {
+        Some(read_string(&thin_key, "", config)?)
     } else {
-        String::from("")
+        None
+    };
+
+    let priority = read_integer(&format!("modules.{}.priority", key), config)?.unwrap_or(0);
+
+    let min_width = match read_integer(&format!("modules.{}.min_width", key), config)? {
+        Some(n) if n < 0 => {
+            return Err(Error::new(ErrorKind::InvalidTypeInConfig,
+                                  &format!("expected usize, got: {:?}", n)));
+        }
+        Some(n) => Some(n as usize),
+        None => None,
     };
 
     let overridden_output = if let Some(val) = config.get(&format!("modules.{}.output", key)) {
@@ -118,10 +302,57 @@ pub fn read_options(key: &str, config: &Config) -> Result<ModuleOptions, Error>
            padding_left: padding_left,
            padding_right: padding_right,
            separator: separator,
+           separator_left: separator_left,
+           separator_right: separator_right,
+           separator_thin: separator_thin,
+           priority: priority,
+           min_width: min_width,
            style: style,
        })
 }
 
+/// Gets a string from a config file, or `default` if `key` isn't
+/// present.
+///
+/// Returns an `Error` if the value isn't a string.
+pub fn read_string(key: &str, default: &str, config: &Config) -> Result<String, Error> {
+    if let Some(val) = config.get(key) {
+        unwrap_value_if_string(val)
+    } else {
+        Ok(default.to_string())
+    }
+}
+
+/// Gets an integer from a config file, or `None` if `key` isn't
+/// present.
+///
+/// Returns an `Error` if the value isn't an integer.
+fn read_integer(key: &str, config: &Config) -> Result<Option<i64>, Error> {
+    match config.get(key) {
+        Some(Value::Integer(n)) => Ok(Some(n)),
+        Some(val) => {
+            Err(Error::new(ErrorKind::InvalidTypeInConfig,
+                           &format!("expected integer, got: {:?}", val)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Gets a boolean from a config file, or `default` if `key` isn't
+/// present.
+///
+/// Returns an `Error` if the value isn't a boolean.
+pub fn read_bool(key: &str, default: bool, config: &Config) -> Result<bool, Error> {
+    match config.get(key) {
+        Some(Value::Boolean(b)) => Ok(b),
+        Some(val) => {
+            Err(Error::new(ErrorKind::InvalidTypeInConfig,
+                           &format!("expected boolean, got: {:?}", val)))
+        }
+        None => Ok(default),
+    }
+}
+
 /// Gets a module's style from a config file.
 ///
 /// `key` refers to the style of the module, for example,
@@ -155,101 +386,8 @@ pub fn read_style(key: &str, config: &Config) -> Result<ModuleStyle, Error> {
        })
 }
 
-/// Formats a string with the given `ModuleOptions` for a specific
-/// `Shell`.
-///
-/// # Parameters
-///
-/// - `s` - the contents of the module to be formatted
-/// - `options` - the background, foreground, padding, etc. to apply
-/// - `next_bg` - the background color, if any, of the next visible module
-/// - `shell` - the type of shell to format the string for
-pub fn format_for_module<S: Into<String>>(s: S,
-                                          options: &ModuleOptions,
-                                          next_bg: Option<Color>,
-                                          shell: Shell)
-                                          -> ANSIString<'static> {
-    let s = if let Some(ref output) = options.output {
-        // Override output if present
-        output.to_owned()
-    } else {
-        // Allow usage of String or &str
-        s.into()
-    };
-    let style = style_from_modulestyle(&options.style);
-
-    // Each shell keeps track of the number of characters that make up
-    // the prompt. The ANSI escape-sequences that color the text will
-    // be accidentally included in this length *unless* we prefix and
-    // suffix them with these shell-specific escape-sequences. We
-    // don't want the shell to mistakenly think there's fewer
-    // characters remaining on the current line than there actually
-    // are.
-    let (len_esc_prefix, len_esc_suffix) = if options.style.background.is_none() &&
-                                              options.style.foreground.is_none() {
-        // But if there aren't any color codes that we need to
-        // escape, don't set the length escape codes because we
-        // don't want the shell to have to deal with them if
-        // they're unnecessary
-        ("", "")
-    } else {
-        match shell {
-            Shell::Bash => ("\\[", "\\]"),
-            Shell::Zsh => ("%{", "%}"),
-            _ => panic!("Your shell is not supported yet!"),
-        }
-    };
-
-    // Every time there is a color escape-sequence, it must be
-    // surrounded by the length escape-codes. We also include the
-    // padding before and after the content.
-    let content = format!("{}{}{}{}{}{}{}{}{}",
-                    len_esc_prefix,
-                    style.prefix(),
-                    len_esc_suffix,
-                    options.padding_left,
-                    s,
-                    options.padding_right,
-                    len_esc_prefix,
-                    style.suffix(),
-                    len_esc_suffix,
-    );
-
-    // We must format the separator differently depending on whether
-    // there exists a visible module after this one or not. Length
-    // escape sequences should only be present if they're really
-    // necessary
-    let (len_esc_prefix, len_esc_suffix) = if next_bg.is_none() &&
-                                              options.style.background.is_none() {
-        ("", "")
-    } else {
-        match shell {
-            Shell::Bash => ("\\[", "\\]"),
-            Shell::Zsh => ("%{", "%}"),
-            _ => panic!("Your shell is not supported yet!"),
-        }
-    };
-
-    let separator_style = ModuleStyle {
-        foreground: options.style.background,
-        background: next_bg,
-        text_properties: options.style.text_properties,
-    };
-    let separator_style = style_from_modulestyle(&separator_style);
-    let separator = format!("{}{}{}{}{}{}{}",
-                            len_esc_prefix,
-                            separator_style.prefix(),
-                            len_esc_suffix,
-                            options.separator,
-                            len_esc_prefix,
-                            separator_style.suffix(),
-                            len_esc_suffix);
-
-    ANSIString::from(format!("{}{}", content, separator))
-}
-
 /// Converts a `ModuleStyle` into an `ansi_term::Style`.
-fn style_from_modulestyle(s: &ModuleStyle) -> Style {
+pub fn style_from_modulestyle(s: &ModuleStyle) -> Style {
     let mut style = s.text_properties.unwrap_or_default();
     if let Some(bg) = s.background {
         style = style.on(bg);
@@ -517,6 +655,11 @@ mod tests {
             padding_left: String::from(" "),
             padding_right: String::from(" "),
             separator: String::from(">"),
+            separator_left: String::new(),
+            separator_right: String::from(""),
+            separator_thin: None,
+            priority: 0,
+            min_width: None,
             style: ModuleStyle::default(),
         };
         c.set("modules.prompt.separator", ">").unwrap();
@@ -528,6 +671,11 @@ mod tests {
             padding_left: String::from("|"),
             padding_right: String::from("/"),
             separator: String::from(" "),
+            separator_left: String::from("<"),
+            separator_right: String::from("["),
+            separator_thin: Some(String::from("|")),
+            priority: -1,
+            min_width: Some(10),
             style: ModuleStyle {
                 foreground: Some(Color::White),
                 background: Some(Color::RGB(6, 47, 200)),
@@ -538,6 +686,11 @@ mod tests {
         c.set("modules.prompt.padding_left", "|").unwrap();
         c.set("modules.prompt.padding_right", "/").unwrap();
         c.set("modules.prompt.separator", " ").unwrap();
+        c.set("modules.prompt.separator_left", "<").unwrap();
+        c.set("modules.prompt.separator_right", "[").unwrap();
+        c.set("modules.prompt.separator_thin", "|").unwrap();
+        c.set("modules.prompt.priority", -1).unwrap();
+        c.set("modules.prompt.min_width", 10).unwrap();
         c.set("modules.prompt.style.foreground", "white")
             .unwrap();
         c.set("modules.prompt.style.background", "(6, 47, 200)")
@@ -547,6 +700,9 @@ mod tests {
         assert_eq!(read_options("prompt", &c), Ok(options));
 
         // Error in one of the options
+        c.set("modules.prompt.min_width", -1).unwrap();
+        assert!(read_options("prompt", &c).is_err());
+        c.set("modules.prompt.min_width", 10).unwrap();
         c.set("modules.prompt.padding_left", true).unwrap();
         assert!(read_options("prompt", &c).is_err());
     }
@@ -682,64 +838,6 @@ mod tests {
         assert!(try_color_from_config("background", &c).is_err());
     }
 
-    #[test]
-    #[should_panic]
-    fn test_panic_on_unsupported_shell() {
-        // We must include at least one color, because we only want to
-        // panic if we're using the length escape sequences AND the
-        // shell is unsupported.
-        let options = ModuleOptions {
-            output: None,
-            padding_left: String::new(),
-            padding_right: String::new(),
-            separator: String::new(),
-            style: ModuleStyle {
-                background: Some(Color::Blue),
-                foreground: None,
-                text_properties: None,
-            },
-        };
-
-        let _ = format_for_module("", &options, None, Shell::Fish);
-    }
-
-    #[test]
-    fn test_format_for_module() {
-        const CONTENT: &'static str = "Hello";
-        const PADDING: &'static str = " ";
-        const SEPARATOR: &'static str = ">";
-
-        let mut options = ModuleOptions {
-            output: None,
-            padding_left: PADDING.to_string(),
-            padding_right: PADDING.to_string(),
-            separator: SEPARATOR.to_string(),
-            style: ModuleStyle {
-                background: Some(Color::Blue),
-                foreground: Some(Color::White),
-                text_properties: Some(Style::default().bold()),
-            },
-        };
-
-        let formatted_string = format_for_module(CONTENT.to_string(), &options, None, Shell::Bash);
-        assert_eq!(format!("\\[\x1B[1;44;37m\\]{}{}{}\\[\x1B[0m\\]\\[\x1B[1;34m\\]{}\\[\x1B[0m\\]",
-                           PADDING,
-                           CONTENT,
-                           PADDING,
-                           SEPARATOR),
-                   format!("{}", formatted_string));
-
-        // Override the output, use ZSH
-        options.output = Some(String::from("modified"));
-        let formatted_string = format_for_module(CONTENT.to_string(), &options, None, Shell::Bash);
-        assert_eq!(format!("\\[\x1B[1;44;37m\\]{}{}{}\\[\x1B[0m\\]\\[\x1B[1;34m\\]{}\\[\x1B[0m\\]",
-                           PADDING,
-                           "modified",
-                           PADDING,
-                           SEPARATOR),
-                   format!("{}", formatted_string));
-    }
-
     #[test]
     fn test_style_from_modulestyle() {
         const CONTENT: &'static str = "Hello";
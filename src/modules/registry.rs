@@ -0,0 +1,267 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, Once};
+use std::thread;
+
+use utils::{Error, ErrorKind, FormatResult};
+
+use modules::{Context, Cwd, Duration, ExitCode, Generic, Git, Jobs, Module, Prompt};
+
+/// How many modules are rendered at once, at most
+const MAX_THREADS: usize = 4;
+
+thread_local! {
+    /// Whether this thread is rendering a module, in which case panics
+    /// are turned into errors instead of being printed
+    static RENDERING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Collection of every module that can be listed in `global.modules`
+/// by name.
+pub struct Registry {
+    modules: HashMap<String, Box<dyn Module>>,
+}
+
+impl Registry {
+    /// Creates a `Registry` without any modules in it.
+    pub fn new() -> Registry {
+        Registry { modules: HashMap::new() }
+    }
+
+    /// Adds a module to the registry, replacing any module that was
+    /// previously registered under the same name.
+    pub fn register(&mut self, module: Box<dyn Module>) {
+        self.modules.insert(module.name().to_string(), module);
+    }
+
+    /// Renders the module called `name`.
+    ///
+    /// Names that don't belong to a registered module are treated as
+    /// user-defined (generic) modules, as long as they have a
+    /// `[modules.<name>]` table in the config file.
+    ///
+    /// Returns an `Error` if the module doesn't exist or fails to
+    /// render.
+    pub fn render(&self, name: &str, ctx: &Context) -> Result<FormatResult, Error> {
+        if let Some(module) = self.modules.get(name) {
+            module.render(ctx)
+        } else if ctx.config
+                      .get(&format!("modules.{}", name))
+                      .is_some() {
+            Generic::new(name).render(ctx)
+        } else {
+            Err(Error::new(ErrorKind::UnknownModule,
+                           &format!("no built-in or user-defined module named {:?}", name)))
+        }
+    }
+
+    /// Renders every module in `names`, up to `MAX_THREADS` at the
+    /// same time, so that a slow module (like git in a big repository)
+    /// doesn't hold up the others. A single module is rendered on the
+    /// current thread.
+    ///
+    /// The results are in the same order as `names`. A module that
+    /// panics results in an `Error` rather than taking the whole
+    /// prompt down with it.
+    pub fn render_all(&self, names: &[String], ctx: &Context) -> Vec<Result<FormatResult, Error>> {
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(names.iter().map(|_| None).collect::<Vec<_>>());
+
+        // Every thread, this one included, keeps taking the next module
+        // that nobody has started on yet
+        let work = || loop {
+            let i = next.fetch_add(1, Ordering::SeqCst);
+            if i >= names.len() {
+                break;
+            }
+            let result = self.render_guarded(&names[i], ctx);
+            results.lock().unwrap_or_else(|e| e.into_inner())[i] = Some(result);
+        };
+
+        thread::scope(|scope| {
+            for _ in 1..names.len().min(MAX_THREADS) {
+                scope.spawn(work);
+            }
+            work();
+        });
+
+        results
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner())
+            .into_iter()
+            .map(|result| result.expect("every module is rendered"))
+            .collect()
+    }
+
+    /// Renders the module called `name`, turning a panic into an
+    /// `Error`.
+    fn render_guarded(&self, name: &str, ctx: &Context) -> Result<FormatResult, Error> {
+        silence_module_panics();
+
+        RENDERING.with(|rendering| rendering.set(true));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.render(name, ctx)));
+        RENDERING.with(|rendering| rendering.set(false));
+
+        result.unwrap_or_else(|_| {
+                                  Err(Error::new(ErrorKind::ModulePanicked,
+                                                 &format!("module {:?} crashed while rendering",
+                                                          name)))
+                              })
+    }
+}
+
+/// Keeps panics in modules from being printed above the prompt, since
+/// they're reported as errors anyway. The panic hook is only replaced
+/// once, and passes every other panic on to the hook it replaced.
+fn silence_module_panics() {
+    static INSTALL: Once = Once::new();
+
+    INSTALL.call_once(|| {
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| if !RENDERING.with(Cell::get) {
+                                     hook(info)
+                                 }));
+    });
+}
+
+impl Default for Registry {
+    /// Creates a `Registry` containing every built-in module.
+    fn default() -> Registry {
+        let mut registry = Registry::new();
+        registry.register(Box::new(Cwd));
+        registry.register(Box::new(Duration));
+        registry.register(Box::new(ExitCode));
+        registry.register(Box::new(Git));
+        registry.register(Box::new(Jobs));
+        registry.register(Box::new(Prompt));
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use ansi_term::Color;
+    use config::Config;
+
+    use modules::{Environment, ModuleOptions, Segment};
+
+    #[test]
+    fn test_registry_render() {
+        let mut c = Config::new();
+        let env = Environment::default();
+        let registry = Registry::default();
+
+        c.set("modules.prompt.style_success.background", "green")
+            .unwrap();
+        c.set("modules.custom.output", "hello").unwrap();
+
+        let ctx = Context::for_test(&c, &env);
+
+        // Built-in modules
+        let result = registry.render("prompt", &ctx).unwrap();
+        assert_eq!(result.options.style.background, Some(Color::Green));
+
+        // User-defined modules
+        let result = registry.render("custom", &ctx).unwrap();
+        assert!(result.output.is_some());
+
+        // Neither built-in nor defined in the config
+        let err = registry.render("typo", &ctx).unwrap_err();
+        assert!(format!("{}", err).starts_with("UnknownModule"));
+    }
+
+    #[test]
+    fn test_registry_register() {
+        struct Hello;
+
+        impl Module for Hello {
+            fn name(&self) -> &str {
+                "hello"
+            }
+
+            fn render(&self, _: &Context) -> Result<FormatResult, Error> {
+                Ok(FormatResult {
+                       output: Some(vec![Segment::new("hello")]),
+                       options: ModuleOptions::default(),
+                   })
+            }
+        }
+
+        let c = Config::new();
+        let env = Environment::default();
+        let ctx = Context::for_test(&c, &env);
+
+        let mut registry = Registry::new();
+        assert!(registry.render("hello", &ctx).is_err());
+
+        registry.register(Box::new(Hello));
+        assert_eq!(registry.render("hello", &ctx).unwrap().output,
+                   Some(vec![Segment::new("hello")]));
+    }
+
+    #[test]
+    fn test_registry_render_all() {
+        struct Sleepy(&'static str);
+
+        impl Module for Sleepy {
+            fn name(&self) -> &str {
+                self.0
+            }
+
+            fn render(&self, _: &Context) -> Result<FormatResult, Error> {
+                thread::sleep(Duration::from_millis(300));
+                Ok(FormatResult {
+                       output: Some(vec![Segment::new(self.0)]),
+                       options: ModuleOptions::default(),
+                   })
+            }
+        }
+
+        struct Broken;
+
+        impl Module for Broken {
+            fn name(&self) -> &str {
+                "broken"
+            }
+
+            fn render(&self, _: &Context) -> Result<FormatResult, Error> {
+                panic!("oops");
+            }
+        }
+
+        let c = Config::new();
+        let env = Environment::default();
+        let ctx = Context::for_test(&c, &env);
+
+        let mut registry = Registry::new();
+        registry.register(Box::new(Sleepy("first")));
+        registry.register(Box::new(Sleepy("second")));
+        registry.register(Box::new(Broken));
+
+        let names: Vec<String> = vec!["first", "broken", "typo", "second"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let start = Instant::now();
+        let results = registry.render_all(&names, &ctx);
+
+        // Both sleepy modules ran at the same time
+        assert!(start.elapsed() < Duration::from_millis(600));
+
+        // Results come back in order, and a crashing module is just
+        // another error
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().unwrap().output,
+                   Some(vec![Segment::new("first")]));
+        assert!(format!("{}", results[1].as_ref().unwrap_err()).starts_with("ModulePanicked"));
+        assert!(format!("{}", results[2].as_ref().unwrap_err()).starts_with("UnknownModule"));
+        assert_eq!(results[3].as_ref().unwrap().output,
+                   Some(vec![Segment::new("second")]));
+    }
+}
@@ -0,0 +1,248 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use utils::{Error, ErrorKind};
+
+use modules::{ModuleStyle, Segment};
+
+/// A format string that decides how a module lays out its parts, like
+/// `"{branch}{dirty?}{ahead? ⇡{ahead}}"`.
+///
+/// * `{name}` is replaced by the value of the variable `name`.
+/// * `{name?...}` only shows everything up to the matching `}` if
+///   `name` has a value. The contents are styled like `name` unless
+///   they say otherwise.
+/// * `{name?}` is short for showing the default rendering of `name`
+///   (usually with a leading space and a symbol) if it has a value.
+/// * `\{`, `\}` and `\\` are literal braces and backslashes.
+#[derive(Debug, PartialEq)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Piece {
+    Text(String),
+    Variable(String),
+    Section { name: String, body: Vec<Piece> },
+}
+
+/// Value of a variable used in a `Template`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variable {
+    /// What `{name}` is replaced with
+    pub value: String,
+    /// What `{name?}` is replaced with
+    pub section: String,
+    /// Style of the variable, and of any section that depends on it
+    pub style: ModuleStyle,
+}
+
+impl Template {
+    /// Parses a format string.
+    ///
+    /// Returns an `Error` if the braces don't match up, or if a
+    /// variable doesn't have a name.
+    pub fn parse(format: &str) -> Result<Template, Error> {
+        let mut chars = format.chars().peekable();
+        let pieces = parse_pieces(&mut chars, false).map_err(|msg| {
+                Error::new(ErrorKind::ConfigParseFailure,
+                           &format!("invalid format {:?}: {}", format, msg))
+            })?;
+
+        Ok(Template { pieces: pieces })
+    }
+
+    /// Lists the names of every variable the template refers to, in
+    /// the order they appear in.
+    pub fn variables(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        collect_variables(&self.pieces, &mut names);
+        names
+    }
+
+    /// Whether the template refers to the variable called `name`
+    pub fn uses(&self, name: &str) -> bool {
+        self.variables().contains(&name)
+    }
+
+    /// Fills in the template. `lookup` returns the value of a
+    /// variable, or `None` if it doesn't have one.
+    pub fn render<F>(&self, lookup: F) -> Vec<Segment>
+        where F: Fn(&str) -> Option<Variable>
+    {
+        let mut segments = Vec::new();
+        render_pieces(&self.pieces, &lookup, ModuleStyle::default(), &mut segments);
+        segments
+    }
+}
+
+/// Parses pieces until the end of the format string, or until the `}`
+/// closing the current section if `nested` is set.
+fn parse_pieces(chars: &mut Peekable<Chars>, nested: bool) -> Result<Vec<Piece>, String> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+
+    loop {
+        match chars.next() {
+            None if nested => return Err(String::from("missing closing '}'")),
+            None => break,
+            Some('}') if nested => break,
+            Some('}') => return Err(String::from("unexpected '}'")),
+            Some('\\') => {
+                match chars.next() {
+                    Some(c) => text.push(c),
+                    None => return Err(String::from("trailing '\\'")),
+                }
+            }
+            Some('{') => {
+                if !text.is_empty() {
+                    pieces.push(Piece::Text(text));
+                    text = String::new();
+                }
+
+                let mut name = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_alphanumeric() || c == '_' {
+                        name.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+
+                if name.is_empty() {
+                    return Err(String::from("expected a variable name after '{'"));
+                }
+
+                match chars.next() {
+                    Some('}') => pieces.push(Piece::Variable(name)),
+                    Some('?') => {
+                        let body = parse_pieces(chars, true)?;
+                        pieces.push(Piece::Section {
+                                        name: name,
+                                        body: body,
+                                    });
+                    }
+                    _ => return Err(format!("expected '}}' or '?' after {:?}", name)),
+                }
+            }
+            Some(c) => text.push(c),
+        }
+    }
+
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+
+    Ok(pieces)
+}
+
+fn collect_variables<'a>(pieces: &'a [Piece], names: &mut Vec<&'a str>) {
+    for piece in pieces {
+        match *piece {
+            Piece::Text(_) => {}
+            Piece::Variable(ref name) => names.push(name),
+            Piece::Section { ref name, ref body } => {
+                names.push(name);
+                collect_variables(body, names);
+            }
+        }
+    }
+}
+
+fn render_pieces<F>(pieces: &[Piece], lookup: &F, style: ModuleStyle, segments: &mut Vec<Segment>)
+    where F: Fn(&str) -> Option<Variable>
+{
+    for piece in pieces {
+        match *piece {
+            Piece::Text(ref text) => segments.push(Segment::styled(text.as_str(), style)),
+            Piece::Variable(ref name) => {
+                if let Some(var) = lookup(name) {
+                    segments.push(Segment::styled(var.value, var.style.or(&style)));
+                }
+            }
+            Piece::Section { ref name, ref body } => {
+                if let Some(var) = lookup(name) {
+                    let style = var.style.or(&style);
+                    if body.is_empty() {
+                        segments.push(Segment::styled(var.section, style));
+                    } else {
+                        render_pieces(body, lookup, style, segments);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ansi_term::Color;
+
+    fn lookup(name: &str) -> Option<Variable> {
+        match name {
+            "branch" => {
+                Some(Variable {
+                         value: String::from("master"),
+                         section: String::from(" master"),
+                         style: ModuleStyle::default(),
+                     })
+            }
+            "ahead" => {
+                Some(Variable {
+                         value: String::from("2"),
+                         section: String::from(" ⇡2"),
+                         style: ModuleStyle {
+                             foreground: Some(Color::Green),
+                             ..ModuleStyle::default()
+                         },
+                     })
+            }
+            _ => None,
+        }
+    }
+
+    fn text(segments: &[Segment]) -> String {
+        segments.iter().map(|s| s.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_template_parse() {
+        let template = Template::parse("{branch}{dirty?}{ahead? ⇡{ahead}}").unwrap();
+        assert_eq!(template.variables(),
+                   vec!["branch", "dirty", "ahead", "ahead"]);
+        assert!(template.uses("dirty"));
+        assert!(!template.uses("behind"));
+
+        assert!(Template::parse("\\{literal\\} \\\\").is_ok());
+        assert!(Template::parse("{branch").is_err());
+        assert!(Template::parse("{ahead? {ahead}").is_err());
+        assert!(Template::parse("branch}").is_err());
+        assert!(Template::parse("{}").is_err());
+        assert!(Template::parse("{branch!}").is_err());
+        assert!(Template::parse("trailing \\").is_err());
+    }
+
+    #[test]
+    fn test_template_render() {
+        let render = |format: &str| Template::parse(format).unwrap().render(lookup);
+
+        assert_eq!(text(&render("{branch}{dirty?}{ahead? ⇡{ahead}}")),
+                   "master ⇡2");
+        assert_eq!(text(&render("{ahead?}{behind? ⇣{behind}}")), " ⇡2");
+        assert_eq!(text(&render("[{branch}] \\{{dirty}\\}")), "[master] {}");
+
+        // Sections are styled like the variable they depend on
+        let green = ModuleStyle {
+            foreground: Some(Color::Green),
+            ..ModuleStyle::default()
+        };
+        assert_eq!(render("{branch}{ahead? +{ahead}}"),
+                   vec![Segment::new("master"),
+                        Segment::styled(" +", green),
+                        Segment::styled("2", green)]);
+    }
+}
@@ -1,16 +1,30 @@
 use std::convert::From;
 use std::error;
 use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::mem;
 use std::num::ParseIntError;
 
-use ansi_term::{ANSIString, Color};
-use config::{Config, Value};
+use config::{Config, File, FileFormat, Value};
+#[cfg(unix)]
+use libc;
+use toml;
 
-/// Type that will be returned when a module is formatted
+use modules::{ModuleOptions, Segment};
+
+/// Type that will be returned when a module is rendered.
+///
+/// Only the content of the module is in here: its separator depends
+/// on the module after it, so separators are drawn once every module
+/// is done rendering.
 #[derive(Debug, Default)]
 pub struct FormatResult {
-    pub output: Option<ANSIString<'static>>,
-    pub next_bg: Option<Color>,
+    /// The content of the module, or `None` if it shouldn't be shown
+    pub output: Option<Vec<Segment>>,
+    /// The padding, separator and style to draw the content with
+    pub options: ModuleOptions,
 }
 
 /// Struct representation of an Error encountered in the program
@@ -29,6 +43,12 @@ pub enum ErrorKind {
     NoSuchMatchInConfig,
     /// Input in config was strictly malformed and couldn't be parsed
     ConfigParseFailure,
+    /// Module in `global.modules` is neither built-in nor user-defined
+    UnknownModule,
+    /// Command-line argument couldn't be parsed
+    InvalidArgument,
+    /// Module crashed while rendering
+    ModulePanicked,
 }
 
 impl Error {
@@ -75,10 +95,140 @@ pub fn ref_get_array(key: &str, config: &Config) -> Option<Vec<Value>> {
     config.get(key).and_then(Value::into_array)
 }
 
+/// Reads the TOML config file at `path`.
+///
+/// A missing file is treated like an empty one. Returns an `Error` if
+/// the file exists but can't be parsed.
+pub fn load_config(path: &str) -> Result<Config, Error> {
+    let mut c = Config::new();
+
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(c),
+        Err(e) => {
+            return Err(Error::new(ErrorKind::ConfigParseFailure,
+                                  &format!("failed to read {:?}: {}", path, e)))
+        }
+    };
+
+    // Crate `config` panics instead of returning an error when it's
+    // given malformed TOML or a datetime, so check for both before
+    // handing the text over.
+    let mut parser = toml::Parser::new(&text);
+    let mut root = match parser.parse() {
+        Some(ref root) if root.values().any(has_datetime) => None,
+        root => root,
+    }
+            .ok_or_else(|| {
+                            Error::new(ErrorKind::ConfigParseFailure,
+                                       &format!("{:?} is not valid TOML", path))
+                        })?;
+    let text = if list_substitutions(&mut root) {
+        toml::Value::Table(root).to_string()
+    } else {
+        text
+    };
+
+    c.merge(File::from_str(&text, FileFormat::Toml))
+        .map_err(|e| {
+                     Error::new(ErrorKind::ConfigParseFailure,
+                                &format!("failed to read {:?}: {}", path, e))
+                 })?;
+    Ok(c)
+}
+
+/// Whether `value` is or contains a TOML datetime.
+fn has_datetime(value: &toml::Value) -> bool {
+    match *value {
+        toml::Value::Datetime(_) => true,
+        toml::Value::Array(ref array) => array.iter().any(has_datetime),
+        toml::Value::Table(ref table) => table.values().any(has_datetime),
+        _ => false,
+    }
+}
+
+/// Turns `modules.cwd.substitutions` from a table that maps each path
+/// to what it's replaced with into an array of tables with a `from`
+/// and a `to`. Returns whether there was such a table.
+///
+/// Crate `config` drops table keys that aren't plain identifiers and
+/// lowercases the others, so the paths can't stay keys.
+fn list_substitutions(root: &mut toml::Table) -> bool {
+    let substitutions = match root.get_mut("modules")
+              .and_then(|modules| modules.lookup_mut("cwd.substitutions")) {
+        Some(substitutions) => substitutions,
+        None => return false,
+    };
+    let table = match *substitutions {
+        toml::Value::Table(ref table) => table.clone(),
+        _ => return false,
+    };
+
+    let list = table.into_iter()
+        .map(|(from, to)| {
+                 let mut substitution = toml::Table::new();
+                 substitution.insert(String::from("from"), toml::Value::String(from));
+                 substitution.insert(String::from("to"), to);
+                 toml::Value::Table(substitution)
+             })
+        .collect();
+    *substitutions = toml::Value::Array(list);
+    true
+}
+
+/// Asks the terminal how many columns wide it is.
+///
+/// Returns `None` if neither stderr nor stdin is a terminal.
+#[cfg(unix)]
+pub fn terminal_width() -> Option<usize> {
+    // The shell captures stdout to build the prompt, but stderr and
+    // stdin are usually still connected to the terminal
+    for &fd in &[libc::STDERR_FILENO, libc::STDIN_FILENO] {
+        let mut size: libc::winsize = unsafe { mem::zeroed() };
+        if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } == 0 && size.ws_col > 0 {
+            return Some(size.ws_col as usize);
+        }
+    }
+    None
+}
+
+#[cfg(not(unix))]
+pub fn terminal_width() -> Option<usize> {
+    None
+}
+
+/// Writes every error out to the end of `log_file`, or to stderr if
+/// no log file was given (or it couldn't be opened).
+pub fn report_errors(errors: &[Error], log_file: Option<&str>) {
+    if errors.is_empty() {
+        return;
+    }
+
+    let file = log_file.and_then(|f| {
+                                     OpenOptions::new()
+                                         .create(true)
+                                         .append(true)
+                                         .open(f)
+                                         .ok()
+                                 });
+    let mut out: Box<dyn Write> = if let Some(f) = file {
+        Box::new(f)
+    } else {
+        Box::new(io::stderr())
+    };
+
+    // There's nowhere left to report a failure to, so ignore it
+    for err in errors {
+        let _ = writeln!(out, "contrail: {}", err);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use tempdir::TempDir;
+
     #[test]
     // The real test is that this compiles successfully without giving
     // a warning about the config file being moved.
@@ -96,4 +246,44 @@ mod tests {
                    Some(vec![Value::Integer(1), Value::Integer(2), Value::Integer(3)]));
         assert_eq!(ref_get_array("boolean", &c), None);
     }
+
+    #[test]
+    fn test_load_config() {
+        let dir = TempDir::new("contrail").unwrap();
+
+        // Missing files are fine
+        let path = dir.path().join("missing.toml");
+        assert!(load_config(path.to_str().unwrap()).is_ok());
+
+        let path = dir.path().join("valid.toml");
+        fs::write(&path, "[global]\nmodules = [\"cwd\"]\n").unwrap();
+        let c = load_config(path.to_str().unwrap()).unwrap();
+        assert!(ref_get_array("global.modules", &c).is_some());
+
+        // Malformed TOML is an error, not a crash
+        let path = dir.path().join("invalid.toml");
+        fs::write(&path, "[global\nmodules = [").unwrap();
+        assert!(load_config(path.to_str().unwrap()).is_err());
+
+        // So is a datetime, which `config` can't represent
+        let path = dir.path().join("datetime.toml");
+        fs::write(&path, "[global]\nsince = 1979-05-27T07:32:00Z\n").unwrap();
+        assert!(load_config(path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_report_errors() {
+        let dir = TempDir::new("contrail").unwrap();
+        let path = dir.path().join("errors.log");
+        let errors = [Error::new(ErrorKind::UnknownModule, "first"),
+                      Error::new(ErrorKind::InvalidArgument, "second")];
+
+        // Errors are appended to the log file
+        report_errors(&errors, path.to_str());
+        report_errors(&errors[..1], path.to_str());
+        assert_eq!(fs::read_to_string(&path).unwrap(),
+                   "contrail: UnknownModule: first\n\
+                    contrail: InvalidArgument: second\n\
+                    contrail: UnknownModule: first\n");
+    }
 }